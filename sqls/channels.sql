@@ -5,5 +5,6 @@ CREATE TABLE channels (
 	name VARCHAR NOT NULL,
 	description VARCHAR NOT NULL,
 	administrator INT NOT NULL REFERENCES users(id),
+	last_seq INT NOT NULL DEFAULT 0,
 	UNIQUE(name)
 );
\ No newline at end of file
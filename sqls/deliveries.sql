@@ -0,0 +1,8 @@
+DROP TABLE IF EXISTS deliveries CASCADE;
+
+CREATE TABLE deliveries (
+	id SERIAL NOT NULL PRIMARY KEY,
+	"user" INT NOT NULL REFERENCES users(id),
+	message INT NOT NULL REFERENCES messages(id) ON DELETE CASCADE,
+	UNIQUE ("user", message)
+);
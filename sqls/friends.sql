@@ -4,5 +4,6 @@ CREATE TABLE friends (
 	id SERIAL NOT NULL PRIMARY KEY,
 	user_a INT NOT NULL REFERENCES users(id),
 	user_b INT NOT NULL REFERENCES users(id),
+	last_seq INT NOT NULL DEFAULT 0,
 	UNIQUE(user_a, user_b)
 );
\ No newline at end of file
@@ -3,6 +3,6 @@ DROP TABLE IF EXISTS join_applications CASCADE;
 CREATE TABLE join_applications (
 	id SERIAL NOT NULL PRIMARY KEY,
 	"from" INT NOT NULL REFERENCES users(id),
-	"to" INT NOT NULL REFERENCES channels(id),
+	"to" INT NOT NULL REFERENCES channels(id) ON DELETE CASCADE,
 	UNIQUE("from", "to")
 );
\ No newline at end of file
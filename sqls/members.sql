@@ -1,8 +1,12 @@
 DROP TABLE IF EXISTS members CASCADE;
+DROP TYPE IF EXISTS member_role;
+
+CREATE TYPE member_role AS ENUM ('owner', 'admin', 'moderator', 'member');
 
 CREATE TABLE members (
 	id SERIAL NOT NULL PRIMARY KEY,
 	"user" INT NOT NULL REFERENCES users(id),
-	channel INT NOT NULL REFERENCES channels(id),
+	channel INT NOT NULL REFERENCES channels(id) ON DELETE CASCADE,
+	role member_role NOT NULL DEFAULT 'member',
 	UNIQUE ("user", channel)
 );
\ No newline at end of file
@@ -0,0 +1,14 @@
+DROP TABLE IF EXISTS messages CASCADE;
+
+CREATE TABLE messages (
+	id SERIAL NOT NULL PRIMARY KEY,
+	"from" INT NOT NULL REFERENCES users(id),
+	"to" INT REFERENCES users(id),
+	channel INT REFERENCES channels(id) ON DELETE CASCADE,
+	seq INT NOT NULL,
+	client_id VARCHAR NOT NULL,
+	content VARCHAR NOT NULL,
+	sent_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+	UNIQUE ("from", client_id),
+	CHECK (("to" IS NULL) <> (channel IS NULL))
+);
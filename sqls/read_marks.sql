@@ -0,0 +1,12 @@
+DROP TABLE IF EXISTS read_marks CASCADE;
+
+CREATE TABLE read_marks (
+	id SERIAL NOT NULL PRIMARY KEY,
+	"user" INT NOT NULL REFERENCES users(id),
+	peer INT REFERENCES users(id),
+	channel INT REFERENCES channels(id) ON DELETE CASCADE,
+	seq INT NOT NULL DEFAULT 0,
+	CHECK ((peer IS NULL) <> (channel IS NULL)),
+	UNIQUE ("user", peer),
+	UNIQUE ("user", channel)
+);
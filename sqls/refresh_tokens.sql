@@ -0,0 +1,9 @@
+DROP TABLE IF EXISTS refresh_tokens CASCADE;
+
+CREATE TABLE refresh_tokens (
+	id SERIAL NOT NULL PRIMARY KEY,
+	"user" INT NOT NULL REFERENCES users(id),
+	token VARCHAR NOT NULL,
+	expires_at TIMESTAMPTZ NOT NULL,
+	UNIQUE(token)
+);
@@ -3,5 +3,8 @@ DROP TABLE IF EXISTS users CASCADE;
 CREATE TABLE users (
 	id SERIAL NOT NULL PRIMARY KEY,
 	name VARCHAR NOT NULL,
-	account INT NOT NULL REFERENCES accounts(id)
+	account INT NOT NULL REFERENCES accounts(id),
+	last_seen TIMESTAMPTZ,
+	-- bumped by a logout everywhere or a new password, tokens of an older generation are rejected
+	token_generation INT NOT NULL DEFAULT 0
 );
\ No newline at end of file
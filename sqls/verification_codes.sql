@@ -0,0 +1,11 @@
+DROP TABLE IF EXISTS verification_codes CASCADE;
+
+CREATE TABLE verification_codes (
+	id SERIAL NOT NULL PRIMARY KEY,
+	phone VARCHAR NOT NULL,
+	code VARCHAR NOT NULL,
+	attempts INT NOT NULL DEFAULT 0,
+	sent_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+	expires_at TIMESTAMPTZ NOT NULL,
+	UNIQUE(phone)
+);
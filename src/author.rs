@@ -1,32 +1,114 @@
 use crate::error::Error;
 use crate::Author;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::{Algorithm, Argon2, Params, Version};
+use chrono::{DateTime, Duration, Utc};
 use hmac::{Hmac, Mac};
 use jwt::{AlgorithmType, Header, SignWithKey, Token, VerifyWithKey};
+use rand::distributions::{Alphanumeric, DistString};
+use rand::Rng;
 use serde::{Deserialize, Serialize};
-use sha2::{Digest, Sha384};
+use sha2::{Digest, Sha256, Sha384};
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use uuid::Uuid;
 
 #[derive(Debug, Clone)]
 pub struct JWTAuthor {
     secret: Vec<u8>,
+    params: Params,
+    token_ttl: Duration,
+    refresh_token_ttl: Duration,
+    revocations: Arc<RwLock<Revocations>>,
+}
+
+// single revoked tokens only live in memory, access tokens are short-lived so a restart exposes one
+// until its exp at most; the generations are a copy of users.token_generation, loaded at startup
+#[derive(Debug, Default)]
+struct Revocations {
+    // jti => exp of the revoked token, kept until the token would have expired anyway
+    tokens: HashMap<String, i64>,
+    // uid => current token generation, tokens of an older generation are rejected
+    generations: HashMap<i32, i32>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Claim {
     pub uid: i32,
+    pub iat: i64,
+    pub exp: i64,
+    pub jti: String,
+    pub gen: i32,
 }
 
 impl JWTAuthor {
-    pub fn new(secret: Vec<u8>) -> Self {
-        Self { secret }
+    pub fn new(secret: Vec<u8>, params: Params, token_ttl: Duration, refresh_token_ttl: Duration) -> Self {
+        Self {
+            secret,
+            params,
+            token_ttl,
+            refresh_token_ttl,
+            revocations: Arc::new(RwLock::new(Revocations::default())),
+        }
+    }
+
+    fn argon2(&self) -> Argon2<'static> {
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, self.params.clone())
+    }
+
+    // checks the signature and expiry, but not the revocations
+    fn decode(&self, token_str: String) -> Result<Claim, Error> {
+        let key: Hmac<Sha384> = Hmac::new_from_slice(&self.secret)?;
+        let token: Token<Header, Claim, _> = token_str.verify_with_key(&key)?;
+        let (_, claim) = token.into();
+        if claim.exp <= Utc::now().timestamp() {
+            return Err(Error::Auth("token expired".into()));
+        }
+        Ok(claim)
     }
 }
 
+pub fn gen_salt() -> String {
+    Alphanumeric.sample_string(&mut rand::thread_rng(), 16)
+}
+
+// one-time codes sent by sms, short enough to be typed in by hand
+pub fn gen_code() -> String {
+    format!("{:06}", rand::thread_rng().gen_range(0..1_000_000))
+}
+
+// passwords stored before the switch to argon2id are a hex encoded SHA-384 of password + salt
+fn legacy_hash(pwd: &str, salt: &str) -> String {
+    let mut hasher = Sha384::new();
+    hasher.update(pwd);
+    hasher.update(salt);
+    format!("{:x}", hasher.finalize())
+}
+
 impl Author for JWTAuthor {
-    fn hash_password(&self, pwd: String, salt: String) -> String {
-        let mut hasher = Sha384::new();
-        hasher.update(pwd);
-        hasher.update(salt);
-        format!("{:x}", hasher.finalize())
+    fn hash_password(&self, pwd: String, salt: String) -> Result<String, Error> {
+        let salt = SaltString::from_b64(&salt)?;
+        Ok(self.argon2().hash_password(pwd.as_bytes(), &salt)?.to_string())
+    }
+
+    fn verify_password(&self, pwd: String, salt: String, hashed: String) -> Result<bool, Error> {
+        if !hashed.starts_with('$') {
+            return Ok(legacy_hash(&pwd, &salt) == hashed);
+        }
+        let hash = PasswordHash::new(&hashed)?;
+        Ok(self.argon2().verify_password(pwd.as_bytes(), &hash).is_ok())
+    }
+
+    fn needs_rehash(&self, hashed: &str) -> bool {
+        match PasswordHash::new(hashed) {
+            Err(_) => true,
+            // the parsed params carry no output length, so only the costs are compared
+            Ok(hash) => {
+                hash.algorithm != Algorithm::Argon2id.ident()
+                    || hash.version != Some(Version::V0x13.into())
+                    || Params::try_from(&hash).map_or(true, |p| p.m_cost() != self.params.m_cost() || p.t_cost() != self.params.t_cost() || p.p_cost() != self.params.p_cost())
+            }
+        }
     }
 
     fn gen_token(&self, uid: i32) -> Result<String, Error> {
@@ -35,13 +117,70 @@ impl Author for JWTAuthor {
             algorithm: AlgorithmType::Hs384,
             ..Default::default()
         };
-        let token = Token::new(header, Claim { uid }).sign_with_key(&key)?;
+        let now = Utc::now();
+        let claim = Claim {
+            uid,
+            iat: now.timestamp(),
+            exp: (now + self.token_ttl).timestamp(),
+            jti: Uuid::new_v4().to_string(),
+            gen: self.revocations.read().unwrap().generations.get(&uid).copied().unwrap_or_default(),
+        };
+        let token = Token::new(header, claim).sign_with_key(&key)?;
         Ok(token.as_str().to_owned())
     }
 
+    fn gen_refresh_token(&self) -> (String, DateTime<Utc>) {
+        (Alphanumeric.sample_string(&mut rand::thread_rng(), 48), Utc::now() + self.refresh_token_ttl)
+    }
+
+    // only the digest of a refresh token is stored, a leaked table can not be used to renew tokens
+    fn hash_refresh_token(&self, token: &str) -> String {
+        format!("{:x}", Sha256::digest(token))
+    }
+
     fn verify(&self, token_str: String) -> Result<i32, crate::error::Error> {
-        let key: Hmac<Sha384> = Hmac::new_from_slice(&self.secret)?;
-        let token: Token<Header, Claim, _> = token_str.verify_with_key(&key)?;
-        Ok(token.claims().uid.clone())
+        let claim = self.decode(token_str)?;
+        let revocations = self.revocations.read().unwrap();
+        if revocations.tokens.contains_key(&claim.jti) || revocations.generations.get(&claim.uid).is_some_and(|gen| claim.gen < *gen) {
+            return Err(Error::Auth("token revoked".into()));
+        }
+        Ok(claim.uid)
+    }
+
+    fn revoke(&self, token: String) -> Result<(), Error> {
+        let claim = self.decode(token)?;
+        let now = Utc::now().timestamp();
+        let mut revocations = self.revocations.write().unwrap();
+        revocations.tokens.retain(|_, exp| *exp > now);
+        revocations.tokens.insert(claim.jti, claim.exp);
+        Ok(())
+    }
+
+    fn revoke_all(&self, uid: i32, generation: i32) {
+        self.revocations.write().unwrap().generations.insert(uid, generation);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn author(params: Params) -> JWTAuthor {
+        JWTAuthor::new(b"test-secret".to_vec(), params, Duration::minutes(15), Duration::days(30))
+    }
+
+    #[test]
+    fn current_hash_needs_no_rehash() {
+        let author = author(Params::default());
+        let hashed = author.hash_password("pw".into(), gen_salt()).unwrap();
+        assert!(!author.needs_rehash(&hashed));
+    }
+
+    #[test]
+    fn outdated_hash_needs_rehash() {
+        let old = author(Params::new(8 * 1024, 1, 1, None).unwrap());
+        let hashed = old.hash_password("pw".into(), gen_salt()).unwrap();
+        assert!(author(Params::default()).needs_rehash(&hashed));
+        assert!(author(Params::default()).needs_rehash(&legacy_hash("pw", "salt")));
     }
 }
@@ -1,5 +1,8 @@
 use crate::error::Error;
-use crate::models::{Account, AccountInsert, ChannelInsert, FriendApplicationInsert, FriendInsert, JoinApplicationInsert, MemberInsert, User, UserInsert};
+use crate::models::{
+    Account, AccountInsert, ChannelInsert, Conversation, ConversationSummary, Friend, FriendApplication, FriendApplicationInsert, FriendInsert, JoinApplication, JoinApplicationInsert, Member,
+    MemberInsert, Message, MessageInsert, RefreshToken, RefreshTokenInsert, Role, User, UserInsert, VerificationCode, VerificationCodeInsert,
+};
 use crate::Dao;
 use sqlx::{query, query_as, Pool, Postgres};
 
@@ -24,6 +27,11 @@ impl Dao for PostgresDao {
         Ok(res.rows_affected())
     }
 
+    async fn get_friends(&self, user: i32) -> Result<Vec<Friend>, Error> {
+        let res = query_as("SELECT * FROM friends WHERE user_a = $1 OR user_b = $1").bind(user).fetch_all(&self.db).await?;
+        Ok(res)
+    }
+
     async fn exists_friend(&self, user_a: i32, user_b: i32) -> Result<bool, Error> {
         let res = query!("SELECT EXISTS(SELECT id FROM friends WHERE user_a = $1 AND user_b = $2 OR user_a = $2 AND user_b = $1)", user_a, user_b)
             .fetch_one(&self.db)
@@ -31,6 +39,44 @@ impl Dao for PostgresDao {
         Ok(res.exists.unwrap())
     }
 
+    async fn get_members(&self, channel_id: i32) -> Result<Vec<Member>, Error> {
+        let res = query_as(r#"SELECT * FROM members WHERE channel = $1"#).bind(channel_id).fetch_all(&self.db).await?;
+        Ok(res)
+    }
+
+    async fn get_member(&self, user_id: i32, channel_id: i32) -> Result<Option<Member>, Error> {
+        let res = query_as(r#"SELECT * FROM members WHERE "user" = $1 AND channel = $2"#)
+            .bind(user_id)
+            .bind(channel_id)
+            .fetch_optional(&self.db)
+            .await?;
+        Ok(res)
+    }
+
+    async fn update_member_role(&self, user_id: i32, channel_id: i32, role: Role) -> Result<u64, Error> {
+        let res = query(r#"UPDATE members SET role = $3 WHERE "user" = $1 AND channel = $2"#)
+            .bind(user_id)
+            .bind(channel_id)
+            .bind(role)
+            .execute(&self.db)
+            .await?;
+        Ok(res.rows_affected())
+    }
+
+    // the old owner stays on as an admin
+    async fn transfer_ownership(&self, channel_id: i32, from: i32, to: i32) -> Result<(), Error> {
+        let mut tx = self.db.begin().await?;
+        query!(r#"UPDATE members SET role = 'admin' WHERE "user" = $1 AND channel = $2"#, from, channel_id)
+            .execute(&mut tx)
+            .await?;
+        query!(r#"UPDATE members SET role = 'owner' WHERE "user" = $1 AND channel = $2"#, to, channel_id)
+            .execute(&mut tx)
+            .await?;
+        query!("UPDATE channels SET administrator = $2 WHERE id = $1", channel_id, to).execute(&mut tx).await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
     async fn exists_member(&self, user_id: i32, channel_id: i32) -> Result<bool, Error> {
         let res = query!(r#"SELECT EXISTS(SELECT id FROM members WHERE "user" = $1 AND channel = $2)"#, user_id, channel_id)
             .fetch_one(&self.db)
@@ -43,6 +89,16 @@ impl Dao for PostgresDao {
         Ok(res)
     }
 
+    async fn get_account_by_id(&self, id: i32) -> Result<Option<Account>, Error> {
+        let res: Option<Account> = query_as("SELECT * FROM accounts WHERE id = $1").bind(id).fetch_optional(&self.db).await?;
+        Ok(res)
+    }
+
+    async fn update_password(&self, id: i32, password: String, salt: String) -> Result<u64, Error> {
+        let res = query!("UPDATE accounts SET password = $2, salt = $3 WHERE id = $1", id, password, salt).execute(&self.db).await?;
+        Ok(res.rows_affected())
+    }
+
     async fn get_user(&self, id: i32) -> Result<Option<User>, Error> {
         let res: Option<User> = query_as("SELECT * FROM users WHERE id = $1").bind(id).fetch_optional(&self.db).await?;
         Ok(res)
@@ -55,6 +111,16 @@ impl Dao for PostgresDao {
         Ok(res.id)
     }
 
+    async fn insert_account_and_user(&self, acct: AccountInsert, name: String) -> Result<i32, Error> {
+        let mut tx = self.db.begin().await?;
+        let account = query!("INSERT INTO accounts (phone, password, salt) VALUES($1, $2, $3) RETURNING id", acct.phone, acct.password, acct.salt)
+            .fetch_one(&mut tx)
+            .await?;
+        let user = query!(r#"INSERT INTO users (name, account) VALUES ($1, $2) RETURNING id"#, name, account.id).fetch_one(&mut tx).await?;
+        tx.commit().await?;
+        Ok(user.id)
+    }
+
     async fn insert_channel(&self, channel: ChannelInsert) -> Result<i32, Error> {
         let res = query!(
             "INSERT INTO channels (name, description, administrator) VALUES($1, $2, $3) RETURNING id",
@@ -67,6 +133,39 @@ impl Dao for PostgresDao {
         Ok(res.id)
     }
 
+    async fn insert_channel_and_member(&self, channel: ChannelInsert) -> Result<i32, Error> {
+        let mut tx = self.db.begin().await?;
+        let res = query!(
+            "INSERT INTO channels (name, description, administrator) VALUES($1, $2, $3) RETURNING id",
+            channel.name,
+            channel.description,
+            channel.administrator
+        )
+        .fetch_one(&mut tx)
+        .await?;
+        query!(r#"INSERT INTO members (channel, "user", role) VALUES ($1, $2, 'owner')"#, res.id, channel.administrator)
+            .execute(&mut tx)
+            .await?;
+        tx.commit().await?;
+        Ok(res.id)
+    }
+
+    async fn update_channel(&self, id: i32, name: Option<String>, description: Option<String>) -> Result<Option<crate::models::Channel>, Error> {
+        let res = query_as("UPDATE channels SET name = COALESCE($2, name), description = COALESCE($3, description) WHERE id = $1 RETURNING *")
+            .bind(id)
+            .bind(name)
+            .bind(description)
+            .fetch_optional(&self.db)
+            .await?;
+        Ok(res)
+    }
+
+    // members, applications, messages and read marks of the channel go with it
+    async fn delete_channel(&self, id: i32) -> Result<u64, Error> {
+        let res = query!("DELETE FROM channels WHERE id = $1", id).execute(&self.db).await?;
+        Ok(res.rows_affected())
+    }
+
     async fn insert_friend(&self, friend: FriendInsert) -> Result<i32, Error> {
         let res = query!("INSERT INTO friends (user_a, user_b) VALUES($1, $2) RETURNING id", friend.user_a, friend.user_b,)
             .fetch_one(&self.db)
@@ -81,6 +180,38 @@ impl Dao for PostgresDao {
         Ok(res.id)
     }
 
+    async fn get_friend_application(&self, from: i32, to: i32) -> Result<Option<FriendApplication>, Error> {
+        let res = query_as(r#"SELECT * FROM friend_applications WHERE "from" = $1 AND "to" = $2"#)
+            .bind(from)
+            .bind(to)
+            .fetch_optional(&self.db)
+            .await?;
+        Ok(res)
+    }
+
+    async fn get_friend_applications(&self, user: i32) -> Result<Vec<FriendApplication>, Error> {
+        let res = query_as(r#"SELECT * FROM friend_applications WHERE "from" = $1 OR "to" = $1 ORDER BY id"#)
+            .bind(user)
+            .fetch_all(&self.db)
+            .await?;
+        Ok(res)
+    }
+
+    async fn approve_friend_application(&self, id: i32) -> Result<i32, Error> {
+        let mut tx = self.db.begin().await?;
+        let app = query!(r#"DELETE FROM friend_applications WHERE id = $1 RETURNING "from", "to""#, id).fetch_one(&mut tx).await?;
+        // an application the other way round is settled by this one as well
+        query!(r#"DELETE FROM friend_applications WHERE "from" = $1 AND "to" = $2"#, app.to, app.from).execute(&mut tx).await?;
+        let friend = query!("INSERT INTO friends (user_a, user_b) VALUES($1, $2) RETURNING id", app.from, app.to).fetch_one(&mut tx).await?;
+        tx.commit().await?;
+        Ok(friend.id)
+    }
+
+    async fn delete_friend_application(&self, id: i32) -> Result<u64, Error> {
+        let res = query!("DELETE FROM friend_applications WHERE id = $1", id).execute(&self.db).await?;
+        Ok(res.rows_affected())
+    }
+
     async fn insert_join_application(&self, app: JoinApplicationInsert) -> Result<i32, Error> {
         let res = query!(r#"INSERT INTO join_applications ("from", "to") VALUES ($1, $2) RETURNING id"#, app.from, app.to)
             .fetch_one(&self.db)
@@ -88,6 +219,42 @@ impl Dao for PostgresDao {
         Ok(res.id)
     }
 
+    async fn get_join_application(&self, from: i32, to: i32) -> Result<Option<JoinApplication>, Error> {
+        let res = query_as(r#"SELECT * FROM join_applications WHERE "from" = $1 AND "to" = $2"#)
+            .bind(from)
+            .bind(to)
+            .fetch_optional(&self.db)
+            .await?;
+        Ok(res)
+    }
+
+    // to the channels where the user holds the role or one above it,
+    // member_role is declared highest first so the roles are matched by name
+    async fn get_join_applications(&self, user: i32, role: Role) -> Result<Vec<JoinApplication>, Error> {
+        let roles: Vec<&str> = Role::ALL.iter().filter(|r| **r >= role).map(Role::as_str).collect();
+        let res = query_as(r#"SELECT j.* FROM join_applications AS j JOIN members AS m ON m.channel = j."to" WHERE m."user" = $1 AND m.role::text = ANY($2) ORDER BY j.id"#)
+            .bind(user)
+            .bind(roles)
+            .fetch_all(&self.db)
+            .await?;
+        Ok(res)
+    }
+
+    async fn approve_join_application(&self, id: i32) -> Result<i32, Error> {
+        let mut tx = self.db.begin().await?;
+        let app = query!(r#"DELETE FROM join_applications WHERE id = $1 RETURNING "from", "to""#, id).fetch_one(&mut tx).await?;
+        let member = query!(r#"INSERT INTO members (channel, "user") VALUES ($1, $2) RETURNING id"#, app.to, app.from)
+            .fetch_one(&mut tx)
+            .await?;
+        tx.commit().await?;
+        Ok(member.id)
+    }
+
+    async fn delete_join_application(&self, id: i32) -> Result<u64, Error> {
+        let res = query!("DELETE FROM join_applications WHERE id = $1", id).execute(&self.db).await?;
+        Ok(res.rows_affected())
+    }
+
     async fn insert_member(&self, member: MemberInsert) -> Result<i32, Error> {
         let res = query!(r#"INSERT INTO members (channel, "user") VALUES ($1, $2) RETURNING id"#, member.channel, member.user)
             .fetch_one(&self.db)
@@ -102,13 +269,231 @@ impl Dao for PostgresDao {
         Ok(res.id)
     }
 
+    async fn get_channel(&self, id: i32) -> Result<Option<crate::models::Channel>, Error> {
+        let res = query_as("SELECT * FROM channels WHERE id = $1").bind(id).fetch_optional(&self.db).await?;
+        Ok(res)
+    }
+
     async fn query_channel(&self, q: String) -> Result<Vec<crate::models::Channel>, Error> {
-        let res = query_as(r#"SELECT * FROM channels WHERE name LIKE '%$1%'"#).bind(q).fetch_all(&self.db).await?;
+        let res = query_as(r#"SELECT * FROM channels WHERE name LIKE '%' || $1 || '%'"#).bind(q).fetch_all(&self.db).await?;
         Ok(res)
     }
 
+    async fn update_last_seen(&self, id: i32) -> Result<u64, Error> {
+        let res = query!("UPDATE users SET last_seen = now() WHERE id = $1", id).execute(&self.db).await?;
+        Ok(res.rows_affected())
+    }
+
+    async fn increase_token_generation(&self, id: i32) -> Result<i32, Error> {
+        let res = query!("UPDATE users SET token_generation = token_generation + 1 WHERE id = $1 RETURNING token_generation", id)
+            .fetch_one(&self.db)
+            .await?;
+        Ok(res.token_generation)
+    }
+
+    async fn get_token_generations(&self) -> Result<Vec<(i32, i32)>, Error> {
+        let res = query!("SELECT id, token_generation FROM users WHERE token_generation > 0").fetch_all(&self.db).await?;
+        Ok(res.into_iter().map(|r| (r.id, r.token_generation)).collect())
+    }
+
     async fn get_user_by_account_id(&self, account: i32) -> Result<Option<User>, Error> {
         let res = query_as(r#"SELECT * FROM users WHERE account = $1"#).bind(account).fetch_optional(&self.db).await?;
         Ok(res)
     }
+
+    async fn insert_message(&self, message: MessageInsert) -> Result<i32, Error> {
+        let res = query!(
+            r#"WITH f AS (UPDATE friends SET last_seq = last_seq + 1 WHERE user_a = $1 AND user_b = $2 OR user_a = $2 AND user_b = $1 RETURNING last_seq),
+            c AS (UPDATE channels SET last_seq = last_seq + 1 WHERE id = $3 RETURNING last_seq)
+            INSERT INTO messages ("from", "to", channel, seq, client_id, content)
+            VALUES ($1, $2, $3, (SELECT last_seq FROM f UNION ALL SELECT last_seq FROM c), $4, $5) RETURNING id"#,
+            message.from,
+            message.to,
+            message.channel,
+            message.client_id,
+            message.content
+        )
+        .fetch_one(&self.db)
+        .await?;
+        Ok(res.id)
+    }
+
+    async fn get_message(&self, id: i32) -> Result<Option<Message>, Error> {
+        let res = query_as(r#"SELECT * FROM messages WHERE id = $1"#).bind(id).fetch_optional(&self.db).await?;
+        Ok(res)
+    }
+
+    async fn get_message_by_client_id(&self, from: i32, client_id: String) -> Result<Option<Message>, Error> {
+        let res = query_as(r#"SELECT * FROM messages WHERE "from" = $1 AND client_id = $2"#)
+            .bind(from)
+            .bind(client_id)
+            .fetch_optional(&self.db)
+            .await?;
+        Ok(res)
+    }
+
+    async fn insert_deliveries(&self, message: i32, users: Vec<i32>) -> Result<u64, Error> {
+        let res = query!(r#"INSERT INTO deliveries ("user", message) SELECT UNNEST($1::INT[]), $2"#, &users, message)
+            .execute(&self.db)
+            .await?;
+        Ok(res.rows_affected())
+    }
+
+    async fn get_undelivered_messages(&self, user: i32) -> Result<Vec<Message>, Error> {
+        let res = query_as(r#"SELECT m.* FROM messages AS m JOIN deliveries AS d ON d.message = m.id WHERE d."user" = $1 ORDER BY m.id"#)
+            .bind(user)
+            .fetch_all(&self.db)
+            .await?;
+        Ok(res)
+    }
+
+    async fn delete_delivery(&self, user: i32, message: i32) -> Result<u64, Error> {
+        let res = query!(r#"DELETE FROM deliveries WHERE "user" = $1 AND message = $2"#, user, message).execute(&self.db).await?;
+        Ok(res.rows_affected())
+    }
+
+    async fn get_direct_messages(&self, user_a: i32, user_b: i32, before: Option<i32>, limit: i64) -> Result<Vec<Message>, Error> {
+        let res = query_as(r#"SELECT * FROM messages WHERE ("from" = $1 AND "to" = $2 OR "from" = $2 AND "to" = $1) AND ($3::INT IS NULL OR id < $3) ORDER BY id DESC LIMIT $4"#)
+            .bind(user_a)
+            .bind(user_b)
+            .bind(before)
+            .bind(limit)
+            .fetch_all(&self.db)
+            .await?;
+        Ok(res)
+    }
+
+    async fn get_channel_messages(&self, channel: i32, before: Option<i32>, limit: i64) -> Result<Vec<Message>, Error> {
+        let res = query_as(r#"SELECT * FROM messages WHERE channel = $1 AND ($2::INT IS NULL OR id < $2) ORDER BY id DESC LIMIT $3"#)
+            .bind(channel)
+            .bind(before)
+            .bind(limit)
+            .fetch_all(&self.db)
+            .await?;
+        Ok(res)
+    }
+
+    async fn update_read_mark(&self, user: i32, conversation: Conversation, seq: i32) -> Result<u64, Error> {
+        let res = match conversation {
+            Conversation::Direct { uid } => {
+                query!(
+                    r#"INSERT INTO read_marks ("user", peer, seq) VALUES ($1, $2, $3)
+                    ON CONFLICT ("user", peer) DO UPDATE SET seq = GREATEST(read_marks.seq, EXCLUDED.seq)"#,
+                    user,
+                    uid,
+                    seq
+                )
+                .execute(&self.db)
+                .await?
+            }
+            Conversation::Channel { cid } => {
+                query!(
+                    r#"INSERT INTO read_marks ("user", channel, seq) VALUES ($1, $2, $3)
+                    ON CONFLICT ("user", channel) DO UPDATE SET seq = GREATEST(read_marks.seq, EXCLUDED.seq)"#,
+                    user,
+                    cid,
+                    seq
+                )
+                .execute(&self.db)
+                .await?
+            }
+        };
+        Ok(res.rows_affected())
+    }
+
+    async fn get_last_seq(&self, user: i32, conversation: Conversation) -> Result<Option<i32>, Error> {
+        let res = match conversation {
+            Conversation::Direct { uid } => query!("SELECT last_seq FROM friends WHERE user_a = $1 AND user_b = $2 OR user_a = $2 AND user_b = $1", user, uid)
+                .fetch_optional(&self.db)
+                .await?
+                .map(|r| r.last_seq),
+            Conversation::Channel { cid } => query!("SELECT last_seq FROM channels WHERE id = $1", cid).fetch_optional(&self.db).await?.map(|r| r.last_seq),
+        };
+        Ok(res)
+    }
+
+    async fn get_conversations(&self, user: i32) -> Result<Vec<ConversationSummary>, Error> {
+        let rows = query!(
+            r#"SELECT f.user_a + f.user_b - $1 AS peer, NULL::INT AS channel, f.last_seq, COALESCE(r.seq, 0) AS read_seq,
+                (SELECT COUNT(*) FROM messages AS m
+                    WHERE (m."from" = f.user_a AND m."to" = f.user_b OR m."from" = f.user_b AND m."to" = f.user_a) AND m."from" <> $1 AND m.seq > COALESCE(r.seq, 0)) AS unread
+            FROM friends AS f LEFT JOIN read_marks AS r ON r."user" = $1 AND r.peer = f.user_a + f.user_b - $1
+            WHERE f.user_a = $1 OR f.user_b = $1
+            UNION ALL
+            SELECT NULL::INT, c.id, c.last_seq, COALESCE(r.seq, 0),
+                (SELECT COUNT(*) FROM messages AS m WHERE m.channel = c.id AND m."from" <> $1 AND m.seq > COALESCE(r.seq, 0))
+            FROM members AS mb JOIN channels AS c ON c.id = mb.channel LEFT JOIN read_marks AS r ON r."user" = $1 AND r.channel = c.id
+            WHERE mb."user" = $1"#,
+            user
+        )
+        .fetch_all(&self.db)
+        .await?;
+        Ok(rows
+            .into_iter()
+            .map(|r| ConversationSummary {
+                conversation: match r.channel {
+                    Some(cid) => Conversation::Channel { cid },
+                    None => Conversation::Direct { uid: r.peer.unwrap_or_default() },
+                },
+                last_seq: r.last_seq.unwrap_or_default(),
+                read_seq: r.read_seq.unwrap_or_default(),
+                unread: r.unread.unwrap_or_default(),
+            })
+            .collect())
+    }
+
+    async fn insert_refresh_token(&self, token: RefreshTokenInsert) -> Result<i32, Error> {
+        let res = query!(
+            r#"INSERT INTO refresh_tokens ("user", token, expires_at) VALUES ($1, $2, $3) RETURNING id"#,
+            token.user,
+            token.token,
+            token.expires_at
+        )
+        .fetch_one(&self.db)
+        .await?;
+        Ok(res.id)
+    }
+
+    async fn take_refresh_token(&self, token: String) -> Result<Option<RefreshToken>, Error> {
+        let res = query_as(r#"DELETE FROM refresh_tokens WHERE token = $1 RETURNING *"#).bind(token).fetch_optional(&self.db).await?;
+        Ok(res)
+    }
+
+    async fn delete_refresh_tokens(&self, uid: i32) -> Result<u64, Error> {
+        let res = query!(r#"DELETE FROM refresh_tokens WHERE "user" = $1"#, uid).execute(&self.db).await?;
+        Ok(res.rows_affected())
+    }
+
+    async fn upsert_verification_code(&self, code: VerificationCodeInsert) -> Result<i32, Error> {
+        let res = query!(
+            r#"
+            INSERT INTO verification_codes (phone, code, expires_at) VALUES ($1, $2, $3)
+            ON CONFLICT (phone) DO UPDATE SET code = EXCLUDED.code, attempts = 0, sent_at = now(), expires_at = EXCLUDED.expires_at
+            RETURNING id"#,
+            code.phone,
+            code.code,
+            code.expires_at
+        )
+        .fetch_one(&self.db)
+        .await?;
+        Ok(res.id)
+    }
+
+    async fn get_verification_code(&self, phone: String) -> Result<Option<VerificationCode>, Error> {
+        let res = query_as("SELECT * FROM verification_codes WHERE phone = $1").bind(phone).fetch_optional(&self.db).await?;
+        Ok(res)
+    }
+
+    async fn increase_code_attempts(&self, phone: String) -> Result<Option<VerificationCode>, Error> {
+        let res = query_as("UPDATE verification_codes SET attempts = attempts + 1 WHERE phone = $1 RETURNING *")
+            .bind(phone)
+            .fetch_optional(&self.db)
+            .await?;
+        Ok(res)
+    }
+
+    async fn delete_verification_code(&self, id: i32) -> Result<u64, Error> {
+        let res = query!("DELETE FROM verification_codes WHERE id = $1", id).execute(&self.db).await?;
+        Ok(res.rows_affected())
+    }
 }
@@ -3,25 +3,131 @@ use std::fmt::Display;
 use actix_web::{http::StatusCode, HttpResponse, HttpResponseBuilder, ResponseError};
 
 #[derive(Debug)]
-pub struct Error(pub String);
+pub enum Error {
+    // missing, invalid, expired or revoked credentials
+    Auth(String),
+    NotFound(String),
+    // authenticated, but not allowed to do this, or not right now
+    Forbidden(String),
+    Validation(String),
+    Conflict(String),
+    // too many attempts, holds the seconds until the next one is accepted
+    TooManyRequests(u64),
+    // the message is only logged, clients get a generic one
+    Internal(String),
+}
+
+impl Error {
+    // stable across releases, clients branch on these rather than on the messages
+    pub fn code(&self) -> &'static str {
+        match self {
+            Error::Auth(_) => "auth",
+            Error::NotFound(_) => "not_found",
+            Error::Forbidden(_) => "forbidden",
+            Error::Validation(_) => "validation",
+            Error::Conflict(_) => "conflict",
+            Error::TooManyRequests(_) => "too_many_requests",
+            Error::Internal(_) => "internal",
+        }
+    }
+
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Error::Auth(_) => StatusCode::UNAUTHORIZED,
+            Error::NotFound(_) => StatusCode::NOT_FOUND,
+            Error::Forbidden(_) => StatusCode::FORBIDDEN,
+            Error::Validation(_) => StatusCode::BAD_REQUEST,
+            Error::Conflict(_) => StatusCode::CONFLICT,
+            Error::TooManyRequests(_) => StatusCode::TOO_MANY_REQUESTS,
+            Error::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    // what can be shown to the client, the details of an internal error stay in the log, see log()
+    pub fn message(&self) -> String {
+        match self {
+            Error::Internal(_) => "internal error".into(),
+            Error::TooManyRequests(secs) => format!("too many attempts, retry after {} seconds", secs),
+            Error::Auth(msg) | Error::NotFound(msg) | Error::Forbidden(msg) | Error::Validation(msg) | Error::Conflict(msg) => msg.clone(),
+        }
+    }
+
+    pub fn retry_after(&self) -> Option<u64> {
+        match self {
+            Error::TooManyRequests(secs) => Some(*secs),
+            _ => None,
+        }
+    }
+
+    // called where an error is written out to a client, only internal errors are worth a log line
+    pub fn log(&self) {
+        if let Error::Internal(msg) = self {
+            log::error!("internal error: {}", msg);
+        }
+    }
+}
 
 impl Display for Error {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}", self.0)
+        match self {
+            Error::Auth(msg) | Error::NotFound(msg) | Error::Forbidden(msg) | Error::Validation(msg) | Error::Conflict(msg) | Error::Internal(msg) => {
+                write!(f, "{}: {}", self.code(), msg)
+            }
+            Error::TooManyRequests(_) => write!(f, "{}: {}", self.code(), self.message()),
+        }
+    }
+}
+
+impl From<sqlx::Error> for Error {
+    fn from(e: sqlx::Error) -> Self {
+        match &e {
+            sqlx::Error::RowNotFound => Error::NotFound("record not exists".into()),
+            sqlx::Error::Database(db) => match db.code().as_deref() {
+                // unique_violation
+                Some("23505") => Error::Conflict("record already exists".into()),
+                // foreign_key_violation
+                Some("23503") => Error::NotFound("referenced record not exists".into()),
+                _ => Error::Internal(e.to_string()),
+            },
+            _ => Error::Internal(e.to_string()),
+        }
     }
 }
 
-impl<E> From<E> for Error
-where
-    E: std::error::Error,
-{
-    fn from(e: E) -> Self {
-        Self(format!("{}", e))
+impl From<jwt::Error> for Error {
+    fn from(_: jwt::Error) -> Self {
+        Error::Auth("invalid token".into())
+    }
+}
+
+impl From<argon2::password_hash::Error> for Error {
+    fn from(e: argon2::password_hash::Error) -> Self {
+        Error::Internal(e.to_string())
+    }
+}
+
+impl From<actix_web::error::BlockingError> for Error {
+    fn from(e: actix_web::error::BlockingError) -> Self {
+        Error::Internal(e.to_string())
+    }
+}
+
+impl From<hmac::digest::InvalidLength> for Error {
+    fn from(e: hmac::digest::InvalidLength) -> Self {
+        Error::Internal(e.to_string())
     }
 }
 
 impl ResponseError for Error {
+    fn status_code(&self) -> StatusCode {
+        self.status()
+    }
+
     fn error_response(&self) -> HttpResponse<actix_web::body::BoxBody> {
-        HttpResponseBuilder::new(StatusCode::INTERNAL_SERVER_ERROR).body(self.0.clone())
+        self.log();
+        HttpResponseBuilder::new(self.status()).json(serde_json::json!({
+            "code": self.code(),
+            "message": self.message(),
+        }))
     }
 }
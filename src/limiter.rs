@@ -0,0 +1,215 @@
+use crate::settings::LoginLimits;
+use std::borrow::Borrow;
+use std::cmp::min;
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+const BASE_BACKOFF: Duration = Duration::from_secs(1);
+// failures older than this are forgotten, unless the lockout is even longer
+const FORGET_AFTER: Duration = Duration::from_secs(60 * 60);
+
+struct Policy {
+    // failures allowed before any backoff
+    free: u32,
+    // failures after which the key is locked out
+    lockout: u32,
+    lockout_for: Duration,
+}
+
+struct Attempts {
+    failures: u32,
+    last: Instant,
+}
+
+impl Attempts {
+    fn retry_after(&self, policy: &Policy) -> Option<Duration> {
+        if self.failures < policy.free {
+            return None;
+        }
+        let wait = if self.failures >= policy.lockout {
+            policy.lockout_for
+        } else {
+            min(BASE_BACKOFF.saturating_mul(2u32.saturating_pow(self.failures - policy.free)), policy.lockout_for)
+        };
+        wait.checked_sub(self.last.elapsed()).filter(|d| !d.is_zero())
+    }
+}
+
+struct Tracker<K> {
+    policy: Policy,
+    attempts: Mutex<HashMap<K, Attempts>>,
+}
+
+impl<K: Eq + Hash> Tracker<K> {
+    fn new(policy: Policy) -> Self {
+        Self {
+            policy,
+            attempts: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // checks and counts the attempt under the same lock, so concurrent attempts can not all slip through
+    fn reserve(&self, key: K) -> Result<(), Duration> {
+        let mut attempts = self.attempts.lock().unwrap();
+        let forget_after = FORGET_AFTER.max(self.policy.lockout_for);
+        attempts.retain(|_, a| a.last.elapsed() < forget_after);
+        if let Some(wait) = attempts.get(&key).and_then(|a| a.retry_after(&self.policy)) {
+            return Err(wait);
+        }
+        let entry = attempts.entry(key).or_insert(Attempts { failures: 0, last: Instant::now() });
+        entry.failures += 1;
+        entry.last = Instant::now();
+        Ok(())
+    }
+
+    // takes back a reserved attempt that turned out not to be a failure
+    fn release<Q: Eq + Hash + ?Sized>(&self, key: &Q)
+    where
+        K: Borrow<Q>,
+    {
+        let mut attempts = self.attempts.lock().unwrap();
+        if let Some(a) = attempts.get_mut(key) {
+            a.failures = a.failures.saturating_sub(1);
+            if a.failures == 0 {
+                attempts.remove(key);
+            }
+        }
+    }
+
+    fn reset<Q: Eq + Hash + ?Sized>(&self, key: &Q)
+    where
+        K: Borrow<Q>,
+    {
+        self.attempts.lock().unwrap().remove(key);
+    }
+}
+
+// tracks failed logins per phone and per ip, every failure past the free ones doubles the wait until the lockout
+pub struct LoginLimiter {
+    phones: Tracker<String>,
+    ips: Tracker<IpAddr>,
+}
+
+impl LoginLimiter {
+    // a phone is guessed by one person, an ip may be shared by a whole household or carrier nat, hence separate limits
+    pub fn new(limits: &LoginLimits) -> Self {
+        let lockout_for = Duration::from_secs(limits.lockout_secs);
+        Self {
+            phones: Tracker::new(Policy {
+                free: limits.phone_free,
+                lockout: limits.phone_lockout,
+                lockout_for,
+            }),
+            ips: Tracker::new(Policy {
+                free: limits.ip_free,
+                lockout: limits.ip_lockout,
+                lockout_for,
+            }),
+        }
+    }
+
+    // every attempt counts as failed until it succeeds, the error is how long the client has to wait
+    pub fn reserve(&self, phone: &str, ip: Option<IpAddr>) -> Result<(), Duration> {
+        self.phones.reserve(phone.to_owned())?;
+        if let Some(ip) = ip {
+            if let Err(wait) = self.ips.reserve(ip) {
+                self.phones.release(phone);
+                return Err(wait);
+            }
+        }
+        Ok(())
+    }
+
+    // only the phone is cleared, one good account must not unlock an ip that is guessing at others
+    pub fn succeed(&self, phone: &str, ip: Option<IpAddr>) {
+        self.phones.reset(phone);
+        if let Some(ip) = ip {
+            self.ips.release(&ip);
+        }
+    }
+
+    // for attempts that failed for other reasons than a wrong password
+    pub fn release(&self, phone: &str, ip: Option<IpAddr>) {
+        self.phones.release(phone);
+        if let Some(ip) = ip {
+            self.ips.release(&ip);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LOCKOUT: Duration = Duration::from_secs(15 * 60);
+
+    fn age(tracker: &Tracker<&'static str>, key: &str, by: Duration) {
+        tracker.attempts.lock().unwrap().get_mut(key).unwrap().last -= by;
+    }
+
+    #[test]
+    fn lockout_expires() {
+        let tracker = Tracker::new(Policy {
+            free: 0,
+            lockout: 1,
+            lockout_for: LOCKOUT,
+        });
+        assert!(tracker.reserve("111").is_ok());
+        assert!(tracker.reserve("111").is_err());
+        assert!(tracker.reserve("222").is_ok());
+        age(&tracker, "111", LOCKOUT);
+        assert!(tracker.reserve("111").is_ok());
+    }
+
+    #[test]
+    fn backoff_doubles_after_free_failures() {
+        let tracker = Tracker::new(Policy {
+            free: 2,
+            lockout: 10,
+            lockout_for: LOCKOUT,
+        });
+        assert!(tracker.reserve("111").is_ok());
+        assert!(tracker.reserve("111").is_ok());
+        assert!(tracker.reserve("111").is_err_and(|wait| wait <= BASE_BACKOFF));
+        age(&tracker, "111", BASE_BACKOFF);
+        assert!(tracker.reserve("111").is_ok());
+        assert!(tracker.reserve("111").is_err_and(|wait| wait > BASE_BACKOFF && wait <= BASE_BACKOFF * 2));
+    }
+
+    #[test]
+    fn released_attempts_do_not_count() {
+        let tracker = Tracker::new(Policy {
+            free: 1,
+            lockout: 10,
+            lockout_for: LOCKOUT,
+        });
+        for _ in 0..5 {
+            assert!(tracker.reserve("111").is_ok());
+            tracker.release("111");
+        }
+        assert!(tracker.attempts.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn success_clears_the_phone_only() {
+        let limiter = LoginLimiter::new(&LoginLimits {
+            phone_free: 1,
+            phone_lockout: 10,
+            ip_free: 5,
+            ip_lockout: 10,
+            lockout_secs: LOCKOUT.as_secs(),
+        });
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+        // a wrong password for 222 keeps its reservation
+        assert!(limiter.reserve("222", Some(ip)).is_ok());
+        assert!(limiter.reserve("111", Some(ip)).is_ok());
+        assert!(limiter.reserve("111", None).is_err());
+        limiter.succeed("111", Some(ip));
+        assert!(limiter.reserve("111", None).is_ok());
+        assert!(limiter.reserve("222", None).is_err());
+        assert_eq!(limiter.ips.attempts.lock().unwrap()[&ip].failures, 1);
+    }
+}
@@ -3,79 +3,227 @@
 mod author;
 mod dao;
 mod error;
+mod limiter;
 mod message;
 mod models;
+mod permission;
+mod presence;
+mod settings;
+mod sms;
 mod websocket;
 
-use std::sync::RwLock;
-
 use crate::author::JWTAuthor;
 use crate::dao::PostgresDao;
 use crate::error::Error;
+use crate::limiter::LoginLimiter;
+use crate::presence::Presence;
+use crate::settings::{Limits, Server, Settings};
+use crate::sms::LogSmsSender;
 use crate::websocket::WS;
-use actix::{self, Addr};
+use actix_web::http::header;
 use actix_web::web::{self, get, Data};
 use actix_web::{App, HttpRequest, HttpResponse, HttpServer};
 use actix_web_actors::ws::{self};
+use chrono::{DateTime, Duration, Utc};
 use dotenv;
-use models::{Account, AccountInsert, Channel, ChannelInsert, FriendApplicationInsert, FriendInsert, JoinApplicationInsert, MemberInsert, User, UserInsert};
+use models::{
+    Account, AccountInsert, Channel, ChannelInsert, Conversation, ConversationSummary, Friend, FriendApplication, FriendApplicationInsert, FriendInsert, JoinApplication, JoinApplicationInsert,
+    Member, MemberInsert, Message, MessageInsert, RefreshToken, RefreshTokenInsert, Role, User, UserInsert, VerificationCode, VerificationCodeInsert,
+};
 use sqlx::{self, postgres::PgPoolOptions};
 use std::collections::HashMap;
+use std::fmt::Display;
+use std::net::{IpAddr, SocketAddr};
+
+const BEARER_PROTOCOL: &str = "bearer";
 
-pub trait Author {
-    fn hash_password(&self, pwd: String, salt: String) -> String;
+pub trait Author: Send + Sync {
+    fn hash_password(&self, pwd: String, salt: String) -> Result<String, Error>;
+    fn verify_password(&self, pwd: String, salt: String, hashed: String) -> Result<bool, Error>;
+    fn needs_rehash(&self, hashed: &str) -> bool;
     fn gen_token(&self, uid: i32) -> Result<String, Error>;
+    fn gen_refresh_token(&self) -> (String, DateTime<Utc>);
+    fn hash_refresh_token(&self, token: &str) -> String;
     fn verify(&self, token: String) -> Result<i32, Error>;
+    fn revoke(&self, token: String) -> Result<(), Error>;
+    fn revoke_all(&self, uid: i32, generation: i32);
 }
 
-async fn start<A, D>(author: Data<A>, users: Data<RwLock<HashMap<String, Option<Addr<WS<A, D>>>>>>, dao: Data<D>, req: HttpRequest, stream: web::Payload) -> Result<HttpResponse, Error>
+pub trait SmsSender {
+    async fn send(&self, phone: String, content: String) -> Result<(), Error>;
+}
+
+// every argument is an extractor, the shared state can only come in one by one
+#[allow(clippy::too_many_arguments)]
+async fn start<A, D, S>(
+    author: Data<A>,
+    presence: Data<Presence<WS<A, D, S>>>,
+    dao: Data<D>,
+    sms: Data<S>,
+    limiter: Data<LoginLimiter>,
+    limits: Data<Limits>,
+    server: Data<Server>,
+    req: HttpRequest,
+    stream: web::Payload,
+) -> Result<HttpResponse, actix_web::Error>
 where
     A: Author + Clone + Unpin + 'static,
     D: Dao + Clone + Unpin + 'static,
+    S: SmsSender + Clone + Unpin + 'static,
 {
-    let actor = WS::new("".into(), author.clone(), users.clone(), dao.clone());
-    let res = ws::start(actor, &req, stream)?;
+    let mut actor = WS::new(author.clone(), presence.clone(), dao.clone(), sms.clone(), limiter.clone(), limits.clone());
+    actor.ip = client_ip(&req, &server.trusted_proxies);
+    if let Some(token) = handshake_token(&req) {
+        match author.verify(token.clone()) {
+            Ok(uid) => {
+                actor.uid = Some(uid);
+                actor.token = Some(token);
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+    let res = ws::WsResponseBuilder::new(actor, &req, stream).protocols(&[BEARER_PROTOCOL]).start()?;
     Ok(res)
 }
 
+// the peer address, unless it is a trusted proxy, then the address it says it forwarded for
+fn client_ip(req: &HttpRequest, trusted_proxies: &[IpAddr]) -> Option<IpAddr> {
+    let peer = req.peer_addr()?.ip();
+    if !trusted_proxies.contains(&peer) {
+        return Some(peer);
+    }
+    let real = req.connection_info().realip_remote_addr()?.to_owned();
+    real.parse::<SocketAddr>().map(|addr| addr.ip()).or_else(|_| real.parse::<IpAddr>()).ok().or(Some(peer))
+}
+
+// browsers can not set headers on a websocket handshake, so besides the Authorization header the token
+// is also accepted as the subprotocol following "bearer" (new WebSocket(url, ["bearer", token])) or as ?token=
+fn handshake_token(req: &HttpRequest) -> Option<String> {
+    let headers = req.headers();
+    if let Some(token) = headers.get(header::AUTHORIZATION).and_then(|v| v.to_str().ok()).and_then(|v| v.strip_prefix("Bearer ")) {
+        return Some(token.trim().to_owned());
+    }
+    if let Some(protocols) = headers.get(header::SEC_WEBSOCKET_PROTOCOL).and_then(|v| v.to_str().ok()) {
+        let mut protocols = protocols.split(',').map(str::trim);
+        if protocols.any(|p| p == BEARER_PROTOCOL) {
+            if let Some(token) = protocols.next() {
+                return Some(token.to_owned());
+            }
+        }
+    }
+    web::Query::<HashMap<String, String>>::from_query(req.query_string()).ok()?.0.remove("token")
+}
+
 pub trait Dao {
     async fn insert_account(&self, account: AccountInsert) -> Result<i32, Error>;
     async fn get_account(&self, phone: String) -> Result<Option<Account>, Error>;
+    async fn get_account_by_id(&self, id: i32) -> Result<Option<Account>, Error>;
+    async fn update_password(&self, id: i32, password: String, salt: String) -> Result<u64, Error>;
+    async fn insert_account_and_user(&self, account: AccountInsert, name: String) -> Result<i32, Error>;
     async fn insert_user(&self, user: UserInsert) -> Result<i32, Error>;
     async fn get_user(&self, id: i32) -> Result<Option<User>, Error>;
     async fn get_user_by_account_id(&self, account: i32) -> Result<Option<User>, Error>;
+    async fn update_last_seen(&self, id: i32) -> Result<u64, Error>;
+    async fn increase_token_generation(&self, id: i32) -> Result<i32, Error>;
+    async fn get_token_generations(&self) -> Result<Vec<(i32, i32)>, Error>;
     async fn insert_channel(&self, channel: ChannelInsert) -> Result<i32, Error>;
+    async fn insert_channel_and_member(&self, channel: ChannelInsert) -> Result<i32, Error>;
+    async fn update_channel(&self, id: i32, name: Option<String>, description: Option<String>) -> Result<Option<Channel>, Error>;
+    async fn delete_channel(&self, id: i32) -> Result<u64, Error>;
+    async fn get_channel(&self, id: i32) -> Result<Option<Channel>, Error>;
     async fn query_channel(&self, q: String) -> Result<Vec<Channel>, Error>;
     async fn insert_friend_application(&self, app: FriendApplicationInsert) -> Result<i32, Error>;
+    async fn get_friend_application(&self, from: i32, to: i32) -> Result<Option<FriendApplication>, Error>;
+    async fn get_friend_applications(&self, user: i32) -> Result<Vec<FriendApplication>, Error>;
+    async fn approve_friend_application(&self, id: i32) -> Result<i32, Error>;
+    async fn delete_friend_application(&self, id: i32) -> Result<u64, Error>;
     async fn insert_join_application(&self, app: JoinApplicationInsert) -> Result<i32, Error>;
+    async fn get_join_application(&self, from: i32, to: i32) -> Result<Option<JoinApplication>, Error>;
+    async fn get_join_applications(&self, user: i32, role: Role) -> Result<Vec<JoinApplication>, Error>;
+    async fn approve_join_application(&self, id: i32) -> Result<i32, Error>;
+    async fn delete_join_application(&self, id: i32) -> Result<u64, Error>;
     async fn insert_friend(&self, friend: FriendInsert) -> Result<i32, Error>;
+    async fn get_friends(&self, user: i32) -> Result<Vec<Friend>, Error>;
     async fn exists_friend(&self, user_a: i32, user_b: i32) -> Result<bool, Error>;
     async fn delete_friend(&self, id: i32) -> Result<u64, Error>;
     async fn insert_member(&self, member: MemberInsert) -> Result<i32, Error>;
     async fn delete_member(&self, id: i32) -> Result<u64, Error>;
     async fn exists_member(&self, user_id: i32, channel_id: i32) -> Result<bool, Error>;
+    async fn get_members(&self, channel_id: i32) -> Result<Vec<Member>, Error>;
+    async fn get_member(&self, user_id: i32, channel_id: i32) -> Result<Option<Member>, Error>;
+    async fn update_member_role(&self, user_id: i32, channel_id: i32, role: Role) -> Result<u64, Error>;
+    async fn transfer_ownership(&self, channel_id: i32, from: i32, to: i32) -> Result<(), Error>;
+    async fn insert_message(&self, message: MessageInsert) -> Result<i32, Error>;
+    async fn get_message(&self, id: i32) -> Result<Option<Message>, Error>;
+    async fn get_message_by_client_id(&self, from: i32, client_id: String) -> Result<Option<Message>, Error>;
+    async fn insert_deliveries(&self, message: i32, users: Vec<i32>) -> Result<u64, Error>;
+    async fn get_undelivered_messages(&self, user: i32) -> Result<Vec<Message>, Error>;
+    async fn delete_delivery(&self, user: i32, message: i32) -> Result<u64, Error>;
+    async fn get_direct_messages(&self, user_a: i32, user_b: i32, before: Option<i32>, limit: i64) -> Result<Vec<Message>, Error>;
+    async fn get_channel_messages(&self, channel: i32, before: Option<i32>, limit: i64) -> Result<Vec<Message>, Error>;
+    async fn update_read_mark(&self, user: i32, conversation: Conversation, seq: i32) -> Result<u64, Error>;
+    async fn get_last_seq(&self, user: i32, conversation: Conversation) -> Result<Option<i32>, Error>;
+    async fn get_conversations(&self, user: i32) -> Result<Vec<ConversationSummary>, Error>;
+    async fn insert_refresh_token(&self, token: RefreshTokenInsert) -> Result<i32, Error>;
+    async fn take_refresh_token(&self, token: String) -> Result<Option<RefreshToken>, Error>;
+    async fn delete_refresh_tokens(&self, uid: i32) -> Result<u64, Error>;
+    async fn upsert_verification_code(&self, code: VerificationCodeInsert) -> Result<i32, Error>;
+    async fn get_verification_code(&self, phone: String) -> Result<Option<VerificationCode>, Error>;
+    async fn increase_code_attempts(&self, phone: String) -> Result<Option<VerificationCode>, Error>;
+    async fn delete_verification_code(&self, id: i32) -> Result<u64, Error>;
 }
 
 #[actix_web::main]
 async fn main() -> Result<(), std::io::Error> {
     env_logger::init();
-    dotenv::dotenv().unwrap();
-    let db = PgPoolOptions::new().max_connections(5).connect(&std::env::var("DATABASE_URL").unwrap()).await.unwrap();
-    let users: Vec<User> = sqlx::query_as("SELECT * FROM users").fetch_all(&db).await.unwrap();
+    // `with-baby-chat schema` prints the json schema of the wire protocol instead of starting the server
+    if std::env::args().nth(1).as_deref() == Some("schema") {
+        println!("{}", serde_json::to_string_pretty(&message::schema()).unwrap());
+        return Ok(());
+    }
+    // the variables may as well come from the environment itself
+    dotenv::dotenv().ok();
+    let settings = Settings::load().unwrap_or_else(|e| exit("invalid configuration", e));
+    let db = PgPoolOptions::new()
+        .max_connections(settings.database.max_connections)
+        .connect(&settings.database.url)
+        .await
+        .unwrap_or_else(|e| exit("failed to connect to database", e));
     let dao = Data::new(PostgresDao::new(db));
-    let users = Data::new(RwLock::new(
-        users.into_iter().map(|u| (u.name, None)).collect::<HashMap<String, Option<Addr<WS<JWTAuthor, PostgresDao>>>>>(),
+    let presence = Data::new(Presence::<WS<JWTAuthor, PostgresDao, LogSmsSender>>::new());
+    let author = Data::new(JWTAuthor::new(
+        settings.auth.secret.as_bytes().to_vec(),
+        settings.auth.argon2.params().unwrap_or_else(|e| exit("invalid configuration", e)),
+        Duration::seconds(settings.auth.token_ttl_secs),
+        Duration::seconds(settings.auth.refresh_token_ttl_secs),
     ));
-    let author = Data::new(JWTAuthor::new("abcdegfh".chars().map(|c| c as u8).collect()));
-    HttpServer::new(move || {
+    // whoever logged out everywhere before the restart stays logged out
+    for (uid, generation) in dao.get_token_generations().await.unwrap_or_else(|e| exit("failed to load token generations", e)) {
+        author.revoke_all(uid, generation);
+    }
+    let sms = Data::new(LogSmsSender::new());
+    let limiter = Data::new(LoginLimiter::new(&settings.limits.login));
+    let limits = Data::new(settings.limits.clone());
+    let server_settings = Data::new(settings.server.clone());
+    let server = HttpServer::new(move || {
         App::new()
             .app_data(author.clone())
-            .app_data(users.clone())
+            .app_data(presence.clone())
             .app_data(dao.clone())
-            .route("/", get().to(start::<JWTAuthor, PostgresDao>))
-    })
-    .bind("0.0.0.0:8000")
-    .unwrap()
-    .run()
-    .await
+            .app_data(sms.clone())
+            .app_data(limiter.clone())
+            .app_data(limits.clone())
+            .app_data(server_settings.clone())
+            .route("/", get().to(start::<JWTAuthor, PostgresDao, LogSmsSender>))
+    });
+    let server = match &settings.server.tls {
+        None => server.bind(settings.server.bind),
+        Some(tls) => server.bind_rustls(settings.server.bind, tls.server_config().unwrap_or_else(|e| exit("invalid tls configuration", e))),
+    };
+    server.unwrap_or_else(|e| exit(&format!("failed to bind {}", settings.server.bind), e)).run().await
+}
+
+fn exit(context: &str, e: impl Display) -> ! {
+    eprintln!("{}: {}", context, e);
+    std::process::exit(1)
 }
@@ -1,98 +1,336 @@
-use crate::models::{Channel, FriendApplication, JoinApplication, User};
+use crate::error::Error;
+use crate::models::{Channel, Conversation, ConversationSummary, FriendApplication, JoinApplication, Message as ChatMessage, Role, User};
+use crate::presence::Session;
 use actix::Message;
+use chrono::{DateTime, Utc};
+use schemars::schema::RootSchema;
+use schemars::{schema_for, JsonSchema};
 use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
 pub struct Login {
     pub phone: String,
     pub password: String,
+    pub device: Option<String>,
 }
 
-impl Message for Login {
-    type Result = ();
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+pub struct Register {
+    pub phone: String,
+    pub password: String,
+    pub name: String,
+    pub device: Option<String>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-pub struct LoginResponse {
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+pub struct RequestCode {
     pub phone: String,
-    pub token: String,
-    pub err: String,
 }
 
-pub struct RepeatLoginWarning;
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct RequestCodeResponse {
+    pub phone: String,
+}
 
-impl Message for RepeatLoginWarning {
-    type Result = ();
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+pub struct LoginWithCode {
+    pub phone: String,
+    pub code: String,
+    pub device: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+pub struct ResetPassword {
+    pub phone: String,
+    pub code: String,
+    pub password: String,
 }
 
-impl Message for LoginResponse {
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct ResetPasswordResponse {
+    pub phone: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+pub struct RefreshToken {
+    pub refresh_token: String,
+}
+
+// every failure takes this shape, code is one of crate::error::Error::code()
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct ErrorReply {
+    pub code: String,
+    pub message: String,
+    pub request_id: Option<String>,
+    // seconds until another attempt is accepted, only set on too_many_requests
+    pub retry_after: Option<u64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct LoginResponse {
+    pub phone: String,
+    pub token: String,
+    pub refresh_token: String,
+}
+
+pub struct Terminate;
+
+impl Message for Terminate {
     type Result = ();
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
 pub enum NotifyLevel {
     Notify,
     Warning,
     Error,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+// how a session encodes its frames, JSON in text frames unless a Hello picks another
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Codec {
+    #[default]
+    Json,
+    // MessagePack in binary frames, with field names kept so the schema still applies
+    Msgpack,
+}
+
+impl Codec {
+    // clients may offer codecs this server does not know, those are skipped rather than rejected
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "json" => Some(Codec::Json),
+            "msgpack" => Some(Codec::Msgpack),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(tag = "type", content = "payload")]
 pub enum Input {
+    // codecs in order of preference, the first one the server knows wins
+    Hello { versions: Vec<u32>, codecs: Option<Vec<String>> },
+    Register(Register),
+    Login(Login),
+    LoginWithCode(LoginWithCode),
+    RequestCode(RequestCode),
+    ResetPassword(ResetPassword),
+    RefreshToken(RefreshToken),
     FindUser { phone: String },
     AddFriend { phone: String },
     FindChannel { q: String },
     JoinChannel { cid: i32 },
-    FriendApplications { applications: Vec<FriendApplication> },
-    JoinApplications { applications: Vec<JoinApplication> },
-    ApproveFriend { phone: i32 },
-    RejectFriend { phone: i32 },
-    ApproveJoin { cid: i32 },
-    RejectJoin { cid: i32 },
+    // the pending ones this user has sent or received
+    FriendApplications,
+    // the pending ones to channels this user administers
+    JoinApplications,
+    // uid of the applicant
+    ApproveFriend { uid: i32 },
+    RejectFriend { uid: i32 },
+    // uid of the applicant
+    ApproveJoin { cid: i32, uid: i32 },
+    RejectJoin { cid: i32, uid: i32 },
+    CreateChannel { name: String, description: String },
+    // fields left out stay as they are
+    UpdateChannel { cid: i32, name: Option<String>, description: Option<String> },
+    DeleteChannel { cid: i32 },
+    // to a role above the one the member has, owner is only ever handed over with TransferOwnership
+    PromoteMember { cid: i32, uid: i32, role: Role },
+    // to a role below the one the member has
+    DemoteMember { cid: i32, uid: i32, role: Role },
+    TransferOwnership { cid: i32, uid: i32 },
+    SendDirectMessage { to: i32, content: String },
+    SendChannelMessage { cid: i32, content: String },
+    AckMessage { mid: i32 },
+    FetchHistory { conversation: Conversation, before: Option<String>, limit: i64 },
+    MarkRead { conversation: Conversation, seq: i32 },
+    ListConversations,
+    Typing { conversation: Conversation },
+    ListSessions,
+    TerminateSession { sid: String },
+    Logout { refresh_token: Option<String> },
+    LogoutAll,
+    ChangePassword { old: String, new: String },
+}
+
+impl Input {
+    // the inputs that don't need a token, everything else runs as an authenticated user
+    pub fn is_public(&self) -> bool {
+        matches!(
+            self,
+            Input::Hello { .. } | Input::Register(_) | Input::Login(_) | Input::LoginWithCode(_) | Input::RequestCode(_) | Input::ResetPassword(_) | Input::RefreshToken(_)
+        )
+    }
 }
 
 impl Message for Input {
     type Result = ();
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+// oldest first, a session speaks the newest one unless it says otherwise with a Hello
+pub const PROTOCOL_VERSIONS: [u32; 1] = [1];
+
+// {"v": 1, "type": "SendDirectMessage", "id": "...", "payload": {...}}
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
 pub struct InputMessage {
-    pub token: String,
+    pub v: u32,
+    // answered as request_id, and the idempotency key of sends: unique per user across all of its
+    // sessions and reconnects, not per connection, e.g. a uuid
+    pub id: String,
+    pub token: Option<String>,
+    #[serde(flatten)]
     pub input: Input,
 }
 
+// all that is read from a frame that is not a valid InputMessage, so the error still answers its id
+#[derive(Debug, Deserialize)]
+pub struct FrameId {
+    pub id: Option<String>,
+}
+
 impl Message for InputMessage {
     type Result = ();
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
 pub enum Result {
     Approved,
     Rejected,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct History {
+    pub conversation: Conversation,
+    pub messages: Vec<ChatMessage>,
+    // hand back as `before` for the next older page, absent on the oldest one
+    pub cursor: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(tag = "type", content = "payload")]
 pub enum Output {
-    LoginResponse { token: String },
+    Hello { version: u32, codec: Codec },
+    LoginResponse(LoginResponse),
+    RequestCodeResponse(RequestCodeResponse),
+    ResetPasswordResponse(ResetPasswordResponse),
     FindUserResponse { user: Option<User> },
     AddFriendResponse { user: Option<User> },
     FindChannelResponse { channels: Vec<Channel> },
     JoinChannelResponse { cid: i32, name: String },
+    FriendApplicationReceived { application: FriendApplication, user: User },
+    FriendApplicationsResponse { incoming: Vec<FriendApplication>, outgoing: Vec<FriendApplication> },
+    ApproveFriendResponse { uid: i32 },
+    RejectFriendResponse { uid: i32 },
     AddFriendResult { uid: i32, result: Result },
-    JoinChannelResult { uid: i32, result: Result },
+    JoinApplicationReceived { application: JoinApplication, user: User },
+    JoinApplicationsResponse { applications: Vec<JoinApplication> },
+    ApproveJoinResponse { cid: i32, uid: i32 },
+    RejectJoinResponse { cid: i32, uid: i32 },
+    JoinChannelResult { cid: i32, result: Result },
+    CreateChannelResponse { channel: Channel },
+    UpdateChannelResponse { channel: Channel },
+    DeleteChannelResponse { cid: i32 },
+    ChannelUpdated { channel: Channel },
+    ChannelDeleted { cid: i32 },
+    PromoteMemberResponse { cid: i32, uid: i32, role: Role },
+    DemoteMemberResponse { cid: i32, uid: i32, role: Role },
+    TransferOwnershipResponse { cid: i32, uid: i32 },
+    MemberRoleChanged { cid: i32, uid: i32, role: Role },
     Notify { level: NotifyLevel, content: String },
+    Error(ErrorReply),
+    SendDirectMessageResponse { message: ChatMessage },
+    DirectMessage { message: ChatMessage },
+    SendChannelMessageResponse { message: ChatMessage },
+    ChannelMessage { message: ChatMessage },
+    AckMessageResponse { mid: i32 },
+    History(History),
+    MarkReadResponse { conversation: Conversation, seq: i32 },
+    ReadReceipt { conversation: Conversation, uid: i32, seq: i32 },
+    Conversations { conversations: Vec<ConversationSummary> },
+    TypingResponse { conversation: Conversation },
+    Typing { conversation: Conversation, uid: i32, expires_in: u64 },
+    PresenceChanged { uid: i32, online: bool, last_seen: DateTime<Utc> },
+    Sessions { current: String, sessions: Vec<Session> },
+    SessionStarted { session: Session },
+    TerminateSessionResponse { sid: String },
+    LogoutResponse,
+    LogoutAllResponse,
+    ChangePasswordResponse { token: String, refresh_token: String },
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
 pub struct OutputMessage {
+    // the id of the input this answers, absent on pushes
+    #[serde(rename = "id")]
+    pub reply_to: Option<String>,
+    #[serde(flatten)]
     pub output: Output,
 }
 
+impl OutputMessage {
+    pub fn new(output: Output) -> Self {
+        Self { reply_to: None, output }
+    }
+
+    pub fn error(e: Error) -> Self {
+        e.log();
+        Self::new(Output::Error(ErrorReply {
+            code: e.code().into(),
+            message: e.message(),
+            request_id: None,
+            retry_after: e.retry_after(),
+        }))
+    }
+
+    // errors carry the id of the failed request in their body as well
+    pub fn in_reply_to(mut self, id: String) -> Self {
+        if let Output::Error(reply) = &mut self.output {
+            reply.request_id = Some(id.clone());
+        }
+        self.reply_to = Some(id);
+        self
+    }
+}
+
+impl From<std::result::Result<Output, Error>> for OutputMessage {
+    fn from(res: std::result::Result<Output, Error>) -> Self {
+        match res {
+            Ok(output) => Self::new(output),
+            Err(e) => Self::error(e),
+        }
+    }
+}
+
 impl Message for OutputMessage {
     type Result = ();
 }
 
+// the version is the one negotiated by the session, so it is only added when the message is written out
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+pub struct OutputEnvelope {
+    pub v: u32,
+    #[serde(flatten)]
+    pub message: OutputMessage,
+}
+
+// every frame on the socket is one of these, clients generate their bindings from its schema
+#[derive(JsonSchema)]
+#[allow(dead_code)]
+#[serde(untagged)]
+enum Frame {
+    Input(InputMessage),
+    Output(OutputEnvelope),
+}
+
+pub fn schema() -> RootSchema {
+    schema_for!(Frame)
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Command {
+    pub id: String,
     pub from: i32,
     pub input: Input,
 }
@@ -1,11 +1,14 @@
+use chrono::{DateTime, Utc};
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use sqlx::FromRow;
 
-#[derive(Debug, Serialize, Deserialize, FromRow)]
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema, FromRow)]
 pub struct User {
     pub id: i32,
     pub name: String,
     pub account: i32,
+    pub last_seen: Option<DateTime<Utc>>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -29,11 +32,12 @@ pub struct AccountInsert {
     pub salt: String,
 }
 
-#[derive(Debug, Serialize, Deserialize, FromRow)]
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema, FromRow)]
 pub struct Channel {
     pub id: i32,
     pub name: String,
     pub description: String,
+    // the owner, kept in step with the roles of the members
     pub administrator: i32,
 }
 
@@ -44,7 +48,7 @@ pub struct ChannelInsert {
     pub administrator: i32,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema, FromRow)]
 pub struct FriendApplication {
     pub id: i32,
     pub from: i32,
@@ -57,7 +61,7 @@ pub struct FriendApplicationInsert {
     pub to: i32,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema, FromRow)]
 pub struct JoinApplication {
     pub id: i32,
     pub from: i32,
@@ -83,11 +87,36 @@ pub struct FriendInsert {
     pub user_b: i32,
 }
 
+// lowest first, so a role outranks the ones before it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize, JsonSchema, sqlx::Type)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(type_name = "member_role", rename_all = "lowercase")]
+pub enum Role {
+    Member,
+    Moderator,
+    Admin,
+    Owner,
+}
+
+impl Role {
+    pub const ALL: [Role; 4] = [Role::Member, Role::Moderator, Role::Admin, Role::Owner];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Role::Member => "member",
+            Role::Moderator => "moderator",
+            Role::Admin => "admin",
+            Role::Owner => "owner",
+        }
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize, FromRow)]
 pub struct Member {
     pub id: i32,
     pub channel: i32,
     pub user: i32,
+    pub role: Role,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -95,3 +124,79 @@ pub struct MemberInsert {
     pub channel: i32,
     pub user: i32,
 }
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema, FromRow)]
+pub struct Message {
+    pub id: i32,
+    pub from: i32,
+    pub to: Option<i32>,
+    pub channel: Option<i32>,
+    pub seq: i32,
+    pub content: String,
+    pub sent_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MessageInsert {
+    pub from: i32,
+    pub to: Option<i32>,
+    pub channel: Option<i32>,
+    pub client_id: String,
+    pub content: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize, JsonSchema)]
+pub enum Conversation {
+    Direct { uid: i32 },
+    Channel { cid: i32 },
+}
+
+impl Conversation {
+    // the other side of a direct conversation knows it by the id of whoever acts in it
+    pub fn for_peers_of(self, uid: i32) -> Self {
+        match self {
+            Conversation::Direct { .. } => Conversation::Direct { uid },
+            Conversation::Channel { .. } => self,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct ConversationSummary {
+    pub conversation: Conversation,
+    pub last_seq: i32,
+    pub read_seq: i32,
+    pub unread: i64,
+}
+
+#[derive(Debug, Serialize, Deserialize, FromRow)]
+pub struct RefreshToken {
+    pub id: i32,
+    pub user: i32,
+    pub token: String,
+    pub expires_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RefreshTokenInsert {
+    pub user: i32,
+    pub token: String,
+    pub expires_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize, Deserialize, FromRow)]
+pub struct VerificationCode {
+    pub id: i32,
+    pub phone: String,
+    pub code: String,
+    pub attempts: i32,
+    pub sent_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct VerificationCodeInsert {
+    pub phone: String,
+    pub code: String,
+    pub expires_at: DateTime<Utc>,
+}
@@ -0,0 +1,53 @@
+use crate::error::Error;
+use crate::models::Role;
+
+// everything that changes a channel, each is checked against the role of whoever asks for it
+#[derive(Debug, Clone, Copy)]
+pub enum ChannelAction {
+    SettleJoin,
+    UpdateChannel,
+    ChangeRole,
+    DeleteChannel,
+    TransferOwnership,
+}
+
+impl ChannelAction {
+    // the lowest role allowed to do it, the roles above it are as well
+    pub fn required_role(&self) -> Role {
+        match self {
+            ChannelAction::SettleJoin => Role::Moderator,
+            ChannelAction::UpdateChannel | ChannelAction::ChangeRole => Role::Admin,
+            ChannelAction::DeleteChannel | ChannelAction::TransferOwnership => Role::Owner,
+        }
+    }
+
+    pub fn check(&self, role: Role) -> Result<(), Error> {
+        let required = self.required_role();
+        if role < required {
+            return Err(Error::Forbidden(format!("requires the {} role in this channel", required.as_str())));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn required_role_per_action() {
+        assert_eq!(ChannelAction::SettleJoin.required_role(), Role::Moderator);
+        assert_eq!(ChannelAction::UpdateChannel.required_role(), Role::Admin);
+        assert_eq!(ChannelAction::ChangeRole.required_role(), Role::Admin);
+        assert_eq!(ChannelAction::DeleteChannel.required_role(), Role::Owner);
+        assert_eq!(ChannelAction::TransferOwnership.required_role(), Role::Owner);
+    }
+
+    #[test]
+    fn higher_roles_pass_the_check() {
+        assert!(ChannelAction::SettleJoin.check(Role::Member).is_err());
+        assert!(ChannelAction::SettleJoin.check(Role::Moderator).is_ok());
+        assert!(ChannelAction::SettleJoin.check(Role::Owner).is_ok());
+        assert!(ChannelAction::DeleteChannel.check(Role::Admin).is_err());
+    }
+}
@@ -0,0 +1,111 @@
+use crate::message::{OutputMessage, Terminate};
+use actix::dev::ToEnvelope;
+use actix::{Actor, Addr, Handler};
+use chrono::{DateTime, Utc};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::RwLock;
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct Session {
+    pub id: String,
+    pub device: String,
+    pub connected_at: DateTime<Utc>,
+}
+
+// the live sessions of every online user, by uid
+type Sessions<T> = HashMap<i32, Vec<(Session, Addr<T>)>>;
+
+pub struct Presence<T: Actor> {
+    sessions: RwLock<Sessions<T>>,
+}
+
+impl<T: Actor> Default for Presence<T> {
+    fn default() -> Self {
+        Self {
+            sessions: RwLock::new(HashMap::new()),
+        }
+    }
+}
+
+impl<T: Actor> Presence<T> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // returns true if this is the first session of the user, that is, the user just came online
+    pub fn connect(&self, uid: i32, session: Session, addr: Addr<T>) -> bool {
+        let mut sessions = self.sessions.write().unwrap();
+        let list = sessions.entry(uid).or_default();
+        list.retain(|(s, _)| s.id != session.id);
+        list.push((session, addr));
+        list.len() == 1
+    }
+
+    // returns true if this was the last session of the user, that is, the user just went offline
+    pub fn disconnect(&self, uid: i32, sid: &str) -> bool {
+        let mut sessions = self.sessions.write().unwrap();
+        match sessions.get_mut(&uid) {
+            None => false,
+            Some(list) => {
+                let before = list.len();
+                list.retain(|(s, _)| s.id != sid);
+                if list.is_empty() {
+                    sessions.remove(&uid);
+                    return before > 0;
+                }
+                false
+            }
+        }
+    }
+
+    pub fn sessions(&self, uid: i32) -> Vec<Session> {
+        match self.sessions.read().unwrap().get(&uid) {
+            None => Vec::new(),
+            Some(list) => list.iter().map(|(s, _)| s.clone()).collect(),
+        }
+    }
+
+    pub fn session(&self, uid: i32, sid: &str) -> Option<Addr<T>> {
+        let sessions = self.sessions.read().unwrap();
+        sessions.get(&uid)?.iter().find(|(s, _)| s.id == sid).map(|(_, a)| a.clone())
+    }
+}
+
+impl<T> Presence<T>
+where
+    T: Actor + Handler<OutputMessage>,
+    T::Context: ToEnvelope<T, OutputMessage>,
+{
+    pub fn send(&self, uid: i32, msg: OutputMessage) {
+        self.send_except(uid, "", msg)
+    }
+
+    // sends to every session of the user but the one given, which already has the message in hand
+    pub fn send_except(&self, uid: i32, sid: &str, msg: OutputMessage) {
+        if let Some(list) = self.sessions.read().unwrap().get(&uid) {
+            for (_, addr) in list.iter().filter(|(s, _)| s.id != sid) {
+                addr.do_send(msg.clone());
+            }
+        }
+    }
+}
+
+impl<T> Presence<T>
+where
+    T: Actor + Handler<Terminate>,
+    T::Context: ToEnvelope<T, Terminate>,
+{
+    pub fn terminate(&self, uid: i32) {
+        self.terminate_except(uid, "")
+    }
+
+    pub fn terminate_except(&self, uid: i32, sid: &str) {
+        if let Some(list) = self.sessions.read().unwrap().get(&uid) {
+            for (_, addr) in list.iter().filter(|(s, _)| s.id != sid) {
+                addr.do_send(Terminate);
+            }
+        }
+    }
+}
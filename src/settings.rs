@@ -0,0 +1,241 @@
+use argon2::Params;
+use config::{ConfigError, Environment, File};
+use rustls::{Certificate, PrivateKey, ServerConfig};
+use serde::{Deserialize, Deserializer};
+use std::fs;
+use std::io::BufReader;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Settings {
+    pub server: Server,
+    pub database: Database,
+    pub auth: Auth,
+    pub limits: Limits,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Server {
+    pub bind: SocketAddr,
+    // plain http when absent, usually because a proxy in front terminates tls
+    pub tls: Option<Tls>,
+    // proxies whose forwarded-for headers are believed, the client ip is the peer address otherwise
+    #[serde(default, deserialize_with = "ip_list")]
+    pub trusted_proxies: Vec<IpAddr>,
+}
+
+// a list in the file, a comma separated string when it comes from the environment
+fn ip_list<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<IpAddr>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum List {
+        Joined(String),
+        Items(Vec<IpAddr>),
+    }
+    match List::deserialize(deserializer)? {
+        List::Items(ips) => Ok(ips),
+        List::Joined(s) => s.split(',').map(str::trim).filter(|s| !s.is_empty()).map(|s| s.parse().map_err(serde::de::Error::custom)).collect(),
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Tls {
+    // pem encoded certificate chain and private key
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Database {
+    pub url: String,
+    pub max_connections: u32,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Auth {
+    pub secret: String,
+    pub token_ttl_secs: i64,
+    pub refresh_token_ttl_secs: i64,
+    pub argon2: Argon2Costs,
+}
+
+// memory in KiB, passes and lanes of argon2id, stored hashes of other costs are upgraded on the next login
+#[derive(Debug, Clone, Deserialize)]
+pub struct Argon2Costs {
+    pub m_cost: u32,
+    pub t_cost: u32,
+    pub p_cost: u32,
+}
+
+impl Argon2Costs {
+    pub fn params(&self) -> Result<Params, ConfigError> {
+        Params::new(self.m_cost, self.t_cost, self.p_cost, None).map_err(|e| ConfigError::Message(format!("auth.argon2: {}", e)))
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Limits {
+    pub max_history: i64,
+    pub code_ttl_secs: i64,
+    pub code_resend_interval_secs: i64,
+    pub max_code_attempts: i32,
+    pub login: LoginLimits,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct LoginLimits {
+    // failed logins allowed before the backoff starts, and after which the phone or ip is locked out
+    pub phone_free: u32,
+    pub phone_lockout: u32,
+    pub ip_free: u32,
+    pub ip_lockout: u32,
+    pub lockout_secs: u64,
+}
+
+const MIN_SECRET_LEN: usize = 32;
+// no sensible default for these, they fail with a key-less "missing field" otherwise
+const REQUIRED: [&str; 2] = ["auth.secret", "database.url"];
+
+impl Settings {
+    // defaults, then the file named by CHAT_CONFIG (config.toml if unset, may be missing), then CHAT_ prefixed
+    // environment variables with __ between the levels, e.g. CHAT_AUTH__SECRET; DATABASE_URL sets database.url
+    pub fn load() -> Result<Self, ConfigError> {
+        let file = std::env::var("CHAT_CONFIG").unwrap_or_else(|_| "config.toml".into());
+        let raw = config::Config::builder()
+            .set_default("server.bind", "0.0.0.0:8000")?
+            .set_default("database.max_connections", 5)?
+            .set_default("auth.token_ttl_secs", 15 * 60)?
+            .set_default("auth.refresh_token_ttl_secs", 30 * 24 * 60 * 60)?
+            .set_default("auth.argon2.m_cost", i64::from(Params::DEFAULT_M_COST))?
+            .set_default("auth.argon2.t_cost", i64::from(Params::DEFAULT_T_COST))?
+            .set_default("auth.argon2.p_cost", i64::from(Params::DEFAULT_P_COST))?
+            .set_default("limits.max_history", 100)?
+            .set_default("limits.code_ttl_secs", 5 * 60)?
+            .set_default("limits.code_resend_interval_secs", 60)?
+            .set_default("limits.max_code_attempts", 5)?
+            .set_default("limits.login.phone_free", 3)?
+            .set_default("limits.login.phone_lockout", 10)?
+            .set_default("limits.login.ip_free", 20)?
+            .set_default("limits.login.ip_lockout", 100)?
+            .set_default("limits.login.lockout_secs", 15 * 60)?
+            .add_source(File::with_name(&file).required(false))
+            .add_source(Environment::with_prefix("CHAT").prefix_separator("_").separator("__"))
+            .set_override_option("database.url", std::env::var("DATABASE_URL").ok())?
+            .build()?;
+        for key in REQUIRED {
+            if raw.get_string(key).is_err() {
+                return Err(ConfigError::Message(format!("{} is required", key)));
+            }
+        }
+        raw.get_string("server.bind")?.parse::<SocketAddr>().map_err(|e| ConfigError::Message(format!("server.bind: {}", e)))?;
+        let settings: Settings = raw.try_deserialize()?;
+        settings.validate()?;
+        Ok(settings)
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        let checks = [
+            (self.auth.secret.len() >= MIN_SECRET_LEN, format!("auth.secret must be at least {} bytes", MIN_SECRET_LEN)),
+            (self.auth.token_ttl_secs > 0, "auth.token_ttl_secs must be positive".into()),
+            (
+                self.auth.refresh_token_ttl_secs > self.auth.token_ttl_secs,
+                "auth.refresh_token_ttl_secs must be longer than auth.token_ttl_secs".into(),
+            ),
+            (
+                self.auth.argon2.params().is_ok(),
+                "auth.argon2 needs t_cost and p_cost of at least 1 and m_cost of at least 8 KiB per lane".into(),
+            ),
+            (self.database.max_connections > 0, "database.max_connections must be positive".into()),
+            (self.limits.max_history > 0, "limits.max_history must be positive".into()),
+            (self.limits.code_ttl_secs > 0, "limits.code_ttl_secs must be positive".into()),
+            (self.limits.code_resend_interval_secs >= 0, "limits.code_resend_interval_secs must not be negative".into()),
+            (self.limits.max_code_attempts > 0, "limits.max_code_attempts must be positive".into()),
+            (
+                self.limits.login.phone_free <= self.limits.login.phone_lockout,
+                "limits.login.phone_free must not exceed limits.login.phone_lockout".into(),
+            ),
+            (
+                self.limits.login.ip_free <= self.limits.login.ip_lockout,
+                "limits.login.ip_free must not exceed limits.login.ip_lockout".into(),
+            ),
+        ];
+        match checks.into_iter().find(|(ok, _)| !ok) {
+            Some((_, msg)) => Err(ConfigError::Message(msg)),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Tls {
+    pub fn server_config(&self) -> Result<ServerConfig, ConfigError> {
+        let read = |path: &PathBuf| fs::read(path).map_err(|e| ConfigError::Message(format!("failed to read {}: {}", path.display(), e)));
+        let certs = rustls_pemfile::certs(&mut BufReader::new(read(&self.cert)?.as_slice())).map_err(|e| ConfigError::Message(format!("invalid certificate {}: {}", self.cert.display(), e)))?;
+        let key = rustls_pemfile::pkcs8_private_keys(&mut BufReader::new(read(&self.key)?.as_slice()))
+            .map_err(|e| ConfigError::Message(format!("invalid private key {}: {}", self.key.display(), e)))?
+            .into_iter()
+            .next()
+            .ok_or_else(|| ConfigError::Message(format!("no pkcs8 private key in {}", self.key.display())))?;
+        ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_single_cert(certs.into_iter().map(Certificate).collect(), PrivateKey(key))
+            .map_err(|e| ConfigError::Message(format!("invalid tls config: {}", e)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings(secret: &str) -> Settings {
+        Settings {
+            server: Server {
+                bind: "127.0.0.1:8000".parse().unwrap(),
+                tls: None,
+                trusted_proxies: vec![],
+            },
+            database: Database {
+                url: "postgres://localhost/chat".into(),
+                max_connections: 5,
+            },
+            auth: Auth {
+                secret: secret.into(),
+                token_ttl_secs: 15 * 60,
+                refresh_token_ttl_secs: 30 * 24 * 60 * 60,
+                argon2: Argon2Costs {
+                    m_cost: Params::DEFAULT_M_COST,
+                    t_cost: Params::DEFAULT_T_COST,
+                    p_cost: Params::DEFAULT_P_COST,
+                },
+            },
+            limits: Limits {
+                max_history: 100,
+                code_ttl_secs: 5 * 60,
+                code_resend_interval_secs: 60,
+                max_code_attempts: 5,
+                login: LoginLimits {
+                    phone_free: 3,
+                    phone_lockout: 10,
+                    ip_free: 20,
+                    ip_lockout: 100,
+                    lockout_secs: 15 * 60,
+                },
+            },
+        }
+    }
+
+    #[test]
+    fn short_secret_is_rejected() {
+        let err = settings("too-short").validate().unwrap_err();
+        assert!(err.to_string().contains("auth.secret"));
+        assert!(settings(&"x".repeat(MIN_SECRET_LEN)).validate().is_ok());
+    }
+
+    #[test]
+    fn argon2_costs_are_checked() {
+        let mut settings = settings(&"x".repeat(MIN_SECRET_LEN));
+        settings.auth.argon2.p_cost = 0;
+        assert!(settings.validate().unwrap_err().to_string().contains("auth.argon2"));
+    }
+}
@@ -0,0 +1,19 @@
+use crate::error::Error;
+use crate::SmsSender;
+
+// doesn't send anything, the messages only show up in the log, for local development and tests
+#[derive(Debug, Clone, Default)]
+pub struct LogSmsSender;
+
+impl LogSmsSender {
+    pub fn new() -> Self {
+        Self
+    }
+}
+
+impl SmsSender for LogSmsSender {
+    async fn send(&self, phone: String, content: String) -> Result<(), Error> {
+        log::info!("sms to {}: {}", phone, content);
+        Ok(())
+    }
+}
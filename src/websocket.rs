@@ -1,268 +1,1160 @@
-use crate::message::{Command, Input, InputMessage, Login, LoginResponse, NotifyLevel, Output, OutputMessage, RepeatLoginWarning};
-use crate::{Author, Dao};
-use actix::{Actor, Addr, AsyncContext, Handler, StreamHandler, WrapFuture};
-use actix_web::web::Data;
-use actix_web_actors::ws::{Message, ProtocolError, WebsocketContext};
+use crate::author::{gen_code, gen_salt};
+use crate::error::Error;
+use crate::limiter::LoginLimiter;
+use crate::message::{
+    Codec, Command, FrameId, History, Input, InputMessage, LoginResponse, NotifyLevel, Output, OutputEnvelope, OutputMessage, RequestCodeResponse, ResetPasswordResponse, Result as ApplicationResult,
+    Terminate, PROTOCOL_VERSIONS,
+};
+use crate::models::{
+    AccountInsert, ChannelInsert, Conversation, FriendApplication, FriendApplicationInsert, JoinApplication, JoinApplicationInsert, Member, Message as ChatMessage, MessageInsert, RefreshTokenInsert,
+    Role, VerificationCodeInsert,
+};
+use crate::permission::ChannelAction;
+use crate::presence::{Presence, Session};
+use crate::settings::Limits;
+use crate::{Author, Dao, SmsSender};
+use actix::{Actor, ActorContext, ActorFutureExt, AsyncContext, Handler, StreamHandler, WrapFuture};
+use actix_web::web::{self, Data};
+use actix_web_actors::ws::{CloseCode, Message, ProtocolError, WebsocketContext};
+use chrono::Utc;
 use std::collections::HashMap;
-use std::sync::RwLock;
+use std::future::{ready, Future};
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+const TYPING_THROTTLE: Duration = Duration::from_secs(3);
+const TYPING_EXPIRY: Duration = Duration::from_secs(5);
+
+// cursors are opaque to clients, they only hand back what the server gave them
+fn encode_cursor(id: i32) -> String {
+    format!("{:08x}", id)
+}
+
+fn login_response(phone: String, res: Result<(String, String), Error>) -> Result<LoginResponse, Error> {
+    res.map(|(token, refresh_token)| LoginResponse { phone, token, refresh_token })
+}
+
+fn decode_cursor(cursor: &str) -> Result<i32, Error> {
+    i32::from_str_radix(cursor, 16).map_err(|_| Error::Validation("invalid cursor".into()))
+}
 
 #[derive(Clone)]
-pub struct WS<A, D>
+pub struct WS<A, D, S>
 where
     A: Author + Clone + Unpin + 'static,
     D: Dao + Clone + Unpin + 'static,
+    S: SmsSender + Clone + Unpin + 'static,
 {
-    pub name: String,
+    pub uid: Option<i32>,
+    pub sid: String,
+    pub device: String,
+    // the token this session was last authenticated with, revoked on logout
+    pub token: Option<String>,
     pub author: Data<A>,
-    pub users: Data<RwLock<HashMap<String, Option<Addr<WS<A, D>>>>>>,
+    pub presence: Data<Presence<WS<A, D, S>>>,
     pub dao: Data<D>,
+    pub sms: Data<S>,
+    // the protocol version this session speaks, see Input::Hello
+    pub version: u32,
+    // the frame encoding this session writes, see Input::Hello
+    pub codec: Codec,
+    pub limiter: Data<LoginLimiter>,
+    pub limits: Data<Limits>,
+    pub ip: Option<IpAddr>,
+    pub typing: HashMap<Conversation, Instant>,
 }
 
-impl<A, D> WS<A, D>
+impl<A, D, S> WS<A, D, S>
 where
     A: Author + Clone + Unpin + 'static,
     D: Dao + Clone + Unpin + 'static,
+    S: SmsSender + Clone + Unpin + 'static,
 {
-    pub fn new(name: String, author: Data<A>, users: Data<RwLock<HashMap<String, Option<Addr<WS<A, D>>>>>>, dao: Data<D>) -> Self {
-        Self { name, author, users, dao }
+    pub fn new(author: Data<A>, presence: Data<Presence<WS<A, D, S>>>, dao: Data<D>, sms: Data<S>, limiter: Data<LoginLimiter>, limits: Data<Limits>) -> Self {
+        Self {
+            uid: None,
+            sid: Uuid::new_v4().to_string(),
+            device: String::new(),
+            token: None,
+            author,
+            presence,
+            dao,
+            sms,
+            version: PROTOCOL_VERSIONS[PROTOCOL_VERSIONS.len() - 1],
+            codec: Codec::default(),
+            limiter,
+            limits,
+            ip: None,
+            typing: HashMap::new(),
+        }
     }
 
-    async fn handle_login(self, phone: String, password: String) -> LoginResponse {
-        match self.dao.get_account(phone.clone()).await {
-            Err(e) => {
-                return LoginResponse {
-                    phone,
-                    token: "".into(),
-                    err: e.to_string(),
+    async fn login(&self, phone: String, password: String) -> Result<(String, String), Error> {
+        let account = self.dao.get_account(phone.clone()).await?.ok_or_else(|| Error::Auth("invalid phone or password".into()))?;
+        if !self.verify_password(password.clone(), account.salt.clone(), account.password.clone()).await? {
+            return Err(Error::Auth("invalid phone or password".into()));
+        }
+        let user = self.dao.get_user_by_account_id(account.id).await?.ok_or(Error::NotFound("user not exists".into()))?;
+        if self.author.needs_rehash(&account.password) {
+            let salt = gen_salt();
+            let rehashed = match self.hash_password(password, salt.clone()).await {
+                Ok(hashed) => self.dao.update_password(account.id, hashed, salt).await,
+                Err(e) => Err(e),
+            };
+            if let Err(e) = rehashed {
+                log::error!("failed to rehash password of account {}: {}", account.id, e);
+            }
+        }
+        self.issue_tokens(user.id).await
+    }
+
+    // argon2 is slow on purpose, so it runs on the blocking pool instead of the arbiter serving the sockets
+    async fn hash_password(&self, password: String, salt: String) -> Result<String, Error> {
+        let author = self.author.clone();
+        web::block(move || author.hash_password(password, salt)).await?
+    }
+
+    async fn verify_password(&self, password: String, salt: String, hashed: String) -> Result<bool, Error> {
+        let author = self.author.clone();
+        web::block(move || author.verify_password(password, salt, hashed)).await?
+    }
+
+    async fn handle_login(self, phone: String, password: String) -> Result<LoginResponse, Error> {
+        let res = self.throttled(&phone, self.login(phone.clone(), password)).await;
+        login_response(phone, res)
+    }
+
+    // passwords and codes are guessed alike, so both count against the same phone and ip
+    async fn throttled<T>(&self, phone: &str, attempt: impl Future<Output = Result<T, Error>>) -> Result<T, Error> {
+        if let Err(wait) = self.limiter.reserve(phone, self.ip) {
+            return Err(Error::TooManyRequests(wait.as_secs() + 1));
+        }
+        let res = attempt.await;
+        match &res {
+            Ok(_) => self.limiter.succeed(phone, self.ip),
+            // only a wrong password or code is kept as a failure
+            Err(Error::Auth(_)) => {}
+            Err(_) => self.limiter.release(phone, self.ip),
+        }
+        res
+    }
+
+    async fn issue_tokens(&self, uid: i32) -> Result<(String, String), Error> {
+        let token = self.author.gen_token(uid)?;
+        let (refresh_token, expires_at) = self.author.gen_refresh_token();
+        self.dao
+            .insert_refresh_token(RefreshTokenInsert {
+                user: uid,
+                token: self.author.hash_refresh_token(&refresh_token),
+                expires_at,
+            })
+            .await?;
+        Ok((token, refresh_token))
+    }
+
+    async fn refresh(&self, refresh_token: String) -> Result<(String, String), Error> {
+        let stored = self
+            .dao
+            .take_refresh_token(self.author.hash_refresh_token(&refresh_token))
+            .await?
+            .ok_or(Error::Auth("invalid refresh token".into()))?;
+        if stored.expires_at <= Utc::now() {
+            return Err(Error::Auth("refresh token expired".into()));
+        }
+        self.issue_tokens(stored.user).await
+    }
+
+    async fn handle_refresh(self, refresh_token: String) -> Result<LoginResponse, Error> {
+        let res = self.refresh(refresh_token).await;
+        login_response("".into(), res)
+    }
+
+    async fn register(&self, phone: String, password: String, name: String) -> Result<(String, String), Error> {
+        if phone.is_empty() || password.is_empty() || name.is_empty() {
+            return Err(Error::Validation("phone, password and name are required".into()));
+        }
+        if self.dao.get_account(phone.clone()).await?.is_some() {
+            return Err(Error::Conflict("phone already registered".into()));
+        }
+        let salt = gen_salt();
+        let account = AccountInsert {
+            phone,
+            password: self.hash_password(password, salt.clone()).await?,
+            salt,
+        };
+        let uid = self.dao.insert_account_and_user(account, name).await?;
+        self.issue_tokens(uid).await
+    }
+
+    async fn handle_register(self, phone: String, password: String, name: String) -> Result<LoginResponse, Error> {
+        let res = self.register(phone.clone(), password, name).await;
+        login_response(phone, res)
+    }
+
+    async fn handle_find_user(self, phone: String) -> Result<Output, Error> {
+        let user = match self.dao.get_account(phone).await? {
+            None => None,
+            Some(a) => self.dao.get_user_by_account_id(a.id).await?,
+        };
+        Ok(Output::FindUserResponse { user })
+    }
+
+    async fn handle_find_channel(self, q: String) -> Result<Output, Error> {
+        let channels = self.dao.query_channel(q).await?;
+        Ok(Output::FindChannelResponse { channels })
+    }
+
+    async fn handle_add_friend(self, from: i32, phone: String) -> Result<Output, Error> {
+        let account = self.dao.get_account(phone).await?.ok_or_else(|| Error::NotFound("user not found".into()))?;
+        let user = self.dao.get_user_by_account_id(account.id).await?.ok_or_else(|| Error::NotFound("user not found".into()))?;
+        if user.id == from {
+            return Err(Error::Validation("cannot add yourself as a friend".into()));
+        }
+        if self.dao.exists_friend(from, user.id).await? {
+            return Err(Error::Conflict("already friends".into()));
+        }
+        let id = match self.dao.insert_friend_application(FriendApplicationInsert { from, to: user.id }).await {
+            Err(Error::Conflict(_)) => return Err(Error::Conflict("friend application already sent".into())),
+            res => res?,
+        };
+        // the applicant goes along so the target can tell who is asking without another lookup
+        if let Some(applicant) = self.dao.get_user(from).await? {
+            let application = FriendApplication { id, from, to: user.id };
+            self.push(user.id, OutputMessage::new(Output::FriendApplicationReceived { application, user: applicant }));
+        }
+        Ok(Output::AddFriendResponse { user: Some(user) })
+    }
+
+    async fn handle_friend_applications(self, uid: i32) -> Result<Output, Error> {
+        let (incoming, outgoing) = self.dao.get_friend_applications(uid).await?.into_iter().partition(|app| app.to == uid);
+        Ok(Output::FriendApplicationsResponse { incoming, outgoing })
+    }
+
+    // only the target of an application gets to settle it
+    async fn handle_settle_friend(self, uid: i32, applicant: i32, approve: bool) -> Result<Output, Error> {
+        let application = self
+            .dao
+            .get_friend_application(applicant, uid)
+            .await?
+            .ok_or_else(|| Error::NotFound("friend application not found".into()))?;
+        let (result, output) = if approve {
+            self.dao.approve_friend_application(application.id).await?;
+            (ApplicationResult::Approved, Output::ApproveFriendResponse { uid: applicant })
+        } else {
+            self.dao.delete_friend_application(application.id).await?;
+            (ApplicationResult::Rejected, Output::RejectFriendResponse { uid: applicant })
+        };
+        self.push(applicant, OutputMessage::new(Output::AddFriendResult { uid, result }));
+        Ok(output)
+    }
+
+    async fn handle_join_channel(self, from: i32, cid: i32) -> Result<Output, Error> {
+        let channel = self.dao.get_channel(cid).await?.ok_or_else(|| Error::NotFound("channel not found".into()))?;
+        if self.dao.exists_member(from, cid).await? {
+            return Err(Error::Conflict("already a member".into()));
+        }
+        let id = match self.dao.insert_join_application(JoinApplicationInsert { from, to: cid }).await {
+            Err(Error::Conflict(_)) => return Err(Error::Conflict("join application already sent".into())),
+            res => res?,
+        };
+        // everyone who may settle it hears about it
+        if let Some(applicant) = self.dao.get_user(from).await? {
+            let application = JoinApplication { id, from, to: cid };
+            for member in self.dao.get_members(cid).await? {
+                if member.role >= ChannelAction::SettleJoin.required_role() {
+                    let output = Output::JoinApplicationReceived {
+                        application: application.clone(),
+                        user: applicant.clone(),
+                    };
+                    self.push(member.user, OutputMessage::new(output));
                 }
             }
-            Ok(acct) => {
-                if let Some(a) = acct {
-                    let hashed_pwd = self.author.hash_password(password, a.salt);
-                    if hashed_pwd != a.password {
-                        return LoginResponse {
-                            phone,
-                            token: "".into(),
-                            err: "invalid phone or password".into(),
-                        };
-                    }
-                    match self.dao.get_user_by_account_id(a.id).await {
-                        Ok(user) => {
-                            if let Some(u) = user {
-                                match self.author.gen_token(u.id) {
-                                    Ok(token) => return LoginResponse { phone, token: token, err: "".into() },
-                                    Err(e) => {
-                                        return LoginResponse {
-                                            phone,
-                                            token: "".into(),
-                                            err: e.to_string(),
-                                        }
-                                    }
-                                }
-                            }
-                            return LoginResponse {
-                                phone,
-                                token: "".into(),
-                                err: "user not exists".into(),
-                            };
-                        }
-                        Err(e) => {
-                            return LoginResponse {
-                                phone,
-                                token: "".into(),
-                                err: e.to_string(),
-                            }
-                        }
-                    }
+        }
+        Ok(Output::JoinChannelResponse { cid, name: channel.name })
+    }
+
+    async fn handle_join_applications(self, uid: i32) -> Result<Output, Error> {
+        let applications = self.dao.get_join_applications(uid, ChannelAction::SettleJoin.required_role()).await?;
+        Ok(Output::JoinApplicationsResponse { applications })
+    }
+
+    async fn handle_settle_join(self, uid: i32, cid: i32, applicant: i32, approve: bool) -> Result<Output, Error> {
+        self.authorize(uid, cid, ChannelAction::SettleJoin).await?;
+        let application = self
+            .dao
+            .get_join_application(applicant, cid)
+            .await?
+            .ok_or_else(|| Error::NotFound("join application not found".into()))?;
+        let (result, output) = if approve {
+            self.dao.approve_join_application(application.id).await?;
+            (ApplicationResult::Approved, Output::ApproveJoinResponse { cid, uid: applicant })
+        } else {
+            self.dao.delete_join_application(application.id).await?;
+            (ApplicationResult::Rejected, Output::RejectJoinResponse { cid, uid: applicant })
+        };
+        self.push(applicant, OutputMessage::new(Output::JoinChannelResult { cid, result }));
+        Ok(output)
+    }
+
+    fn channel_fields(name: Option<String>, description: Option<String>) -> Result<(Option<String>, Option<String>), Error> {
+        let name = name.map(|name| name.trim().to_string());
+        if name.as_deref() == Some("") {
+            return Err(Error::Validation("channel name is required".into()));
+        }
+        Ok((name, description))
+    }
+
+    // the name is all a client gets to pick, so taking one that is in use is their mistake to fix
+    fn channel_name_taken(e: Error) -> Error {
+        match e {
+            Error::Conflict(_) => Error::Validation("channel name already taken".into()),
+            e => e,
+        }
+    }
+
+    async fn handle_create_channel(self, uid: i32, name: String, description: String) -> Result<Output, Error> {
+        let (name, _) = Self::channel_fields(Some(name), None)?;
+        let channel = ChannelInsert {
+            name: name.unwrap_or_default(),
+            description,
+            administrator: uid,
+        };
+        let id = self.dao.insert_channel_and_member(channel).await.map_err(Self::channel_name_taken)?;
+        let channel = self.dao.get_channel(id).await?.ok_or_else(|| Error::NotFound("channel not found".into()))?;
+        Ok(Output::CreateChannelResponse { channel })
+    }
+
+    // every command that changes a channel goes through here first
+    async fn authorize(&self, uid: i32, cid: i32, action: ChannelAction) -> Result<Member, Error> {
+        self.dao.get_channel(cid).await?.ok_or_else(|| Error::NotFound("channel not found".into()))?;
+        let member = self.dao.get_member(uid, cid).await?.ok_or_else(|| Error::Forbidden("you are not a member of this channel".into()))?;
+        action.check(member.role)?;
+        Ok(member)
+    }
+
+    // to the other members, and to the other sessions of the one who made the change
+    async fn notify_members(&self, uid: i32, cid: i32, output: Output) -> Result<(), Error> {
+        let members = self.dao.get_members(cid).await?;
+        self.notify(uid, &members, output);
+        Ok(())
+    }
+
+    fn notify(&self, uid: i32, members: &[Member], output: Output) {
+        for member in members {
+            if member.user != uid {
+                self.push(member.user, OutputMessage::new(output.clone()));
+            }
+        }
+        self.presence.send_except(uid, &self.sid, OutputMessage::new(output));
+    }
+
+    async fn handle_update_channel(self, uid: i32, cid: i32, name: Option<String>, description: Option<String>) -> Result<Output, Error> {
+        let (name, description) = Self::channel_fields(name, description)?;
+        self.authorize(uid, cid, ChannelAction::UpdateChannel).await?;
+        let channel = self
+            .dao
+            .update_channel(cid, name, description)
+            .await
+            .map_err(Self::channel_name_taken)?
+            .ok_or_else(|| Error::NotFound("channel not found".into()))?;
+        self.notify_members(uid, cid, Output::ChannelUpdated { channel: channel.clone() }).await?;
+        Ok(Output::UpdateChannelResponse { channel })
+    }
+
+    async fn handle_delete_channel(self, uid: i32, cid: i32) -> Result<Output, Error> {
+        self.authorize(uid, cid, ChannelAction::DeleteChannel).await?;
+        // the members are gone along with the channel, so they are collected first
+        let members = self.dao.get_members(cid).await?;
+        if self.dao.delete_channel(cid).await? == 0 {
+            return Err(Error::NotFound("channel not found".into()));
+        }
+        self.notify(uid, &members, Output::ChannelDeleted { cid });
+        Ok(Output::DeleteChannelResponse { cid })
+    }
+
+    // nobody manages their own role, nor anyone with a role as high as their own
+    async fn handle_change_role(self, uid: i32, cid: i32, target: i32, role: Role, promote: bool) -> Result<Output, Error> {
+        let member = self.authorize(uid, cid, ChannelAction::ChangeRole).await?;
+        if role == Role::Owner {
+            return Err(Error::Validation("ownership can only be transferred".into()));
+        }
+        if target == uid {
+            return Err(Error::Validation("cannot change your own role".into()));
+        }
+        let current = self.dao.get_member(target, cid).await?.ok_or_else(|| Error::NotFound("not a member of this channel".into()))?;
+        if current.role >= member.role || role >= member.role {
+            return Err(Error::Forbidden("can only manage roles below your own".into()));
+        }
+        if promote && role <= current.role || !promote && role >= current.role {
+            let direction = if promote { "above" } else { "below" };
+            return Err(Error::Validation(format!("the new role must be {} {}", direction, current.role.as_str())));
+        }
+        self.dao.update_member_role(target, cid, role).await?;
+        self.notify_members(uid, cid, Output::MemberRoleChanged { cid, uid: target, role }).await?;
+        Ok(if promote {
+            Output::PromoteMemberResponse { cid, uid: target, role }
+        } else {
+            Output::DemoteMemberResponse { cid, uid: target, role }
+        })
+    }
+
+    async fn handle_transfer_ownership(self, uid: i32, cid: i32, target: i32) -> Result<Output, Error> {
+        self.authorize(uid, cid, ChannelAction::TransferOwnership).await?;
+        if target == uid {
+            return Err(Error::Validation("already the owner".into()));
+        }
+        if !self.dao.exists_member(target, cid).await? {
+            return Err(Error::NotFound("not a member of this channel".into()));
+        }
+        self.dao.transfer_ownership(cid, uid, target).await?;
+        let members = self.dao.get_members(cid).await?;
+        self.notify(uid, &members, Output::MemberRoleChanged { cid, uid: target, role: Role::Owner });
+        self.notify(uid, &members, Output::MemberRoleChanged { cid, uid, role: Role::Admin });
+        Ok(Output::TransferOwnershipResponse { cid, uid: target })
+    }
+
+    // a retry carries the id of the original send, the same id on a different message is a client bug, not a retry
+    async fn sent_before(&self, from: i32, client_id: &str, to: Option<i32>, channel: Option<i32>, content: &str) -> Result<Option<ChatMessage>, Error> {
+        match self.dao.get_message_by_client_id(from, client_id.to_owned()).await? {
+            Some(message) if message.to == to && message.channel == channel && message.content == content => Ok(Some(message)),
+            Some(_) => Err(Error::Conflict("message id already used for another message".into())),
+            None => Ok(None),
+        }
+    }
+
+    async fn send_direct_message(&self, from: i32, client_id: String, to: i32, content: String) -> Result<ChatMessage, Error> {
+        if !self.dao.exists_friend(from, to).await? {
+            return Err(Error::Forbidden("recipient is not your friend".into()));
+        }
+        if let Some(message) = self.sent_before(from, &client_id, Some(to), None, &content).await? {
+            return Ok(message);
+        }
+        let id = self
+            .dao
+            .insert_message(MessageInsert {
+                from,
+                to: Some(to),
+                channel: None,
+                client_id,
+                content,
+            })
+            .await?;
+        let message = self.dao.get_message(id).await?.ok_or(Error::NotFound("message not exists".into()))?;
+        self.dao.insert_deliveries(id, vec![to]).await?;
+        self.push(to, OutputMessage::new(Output::DirectMessage { message: message.clone() }));
+        self.presence.send_except(from, &self.sid, OutputMessage::new(Output::DirectMessage { message: message.clone() }));
+        Ok(message)
+    }
+
+    async fn handle_send_direct_message(self, from: i32, client_id: String, to: i32, content: String) -> Result<Output, Error> {
+        let message = self.send_direct_message(from, client_id, to, content).await?;
+        Ok(Output::SendDirectMessageResponse { message })
+    }
+
+    async fn send_channel_message(&self, from: i32, client_id: String, cid: i32, content: String) -> Result<ChatMessage, Error> {
+        let recipients = self.participants(from, Conversation::Channel { cid }).await?;
+        if let Some(message) = self.sent_before(from, &client_id, None, Some(cid), &content).await? {
+            return Ok(message);
+        }
+        let id = self
+            .dao
+            .insert_message(MessageInsert {
+                from,
+                to: None,
+                channel: Some(cid),
+                client_id,
+                content,
+            })
+            .await?;
+        let message = self.dao.get_message(id).await?.ok_or(Error::NotFound("message not exists".into()))?;
+        self.dao.insert_deliveries(id, recipients.clone()).await?;
+        for uid in recipients {
+            self.push(uid, OutputMessage::new(Output::ChannelMessage { message: message.clone() }));
+        }
+        self.presence.send_except(from, &self.sid, OutputMessage::new(Output::ChannelMessage { message: message.clone() }));
+        Ok(message)
+    }
+
+    async fn handle_send_channel_message(self, from: i32, client_id: String, cid: i32, content: String) -> Result<Output, Error> {
+        let message = self.send_channel_message(from, client_id, cid, content).await?;
+        Ok(Output::SendChannelMessageResponse { message })
+    }
+
+    async fn handle_deliver_pending(self, uid: i32) -> Vec<OutputMessage> {
+        match self.dao.get_undelivered_messages(uid).await {
+            Err(e) => vec![OutputMessage::error(e)],
+            Ok(messages) => messages
+                .into_iter()
+                .map(|message| {
+                    OutputMessage::new(if message.channel.is_some() {
+                        Output::ChannelMessage { message }
+                    } else {
+                        Output::DirectMessage { message }
+                    })
+                })
+                .collect(),
+        }
+    }
+
+    async fn handle_ack_message(self, uid: i32, mid: i32) -> Result<Output, Error> {
+        self.dao.delete_delivery(uid, mid).await?;
+        Ok(Output::AckMessageResponse { mid })
+    }
+
+    async fn handle_fetch_history(self, uid: i32, conversation: Conversation, before: Option<String>, limit: i64) -> Result<Output, Error> {
+        let before = match before {
+            Some(cursor) => Some(decode_cursor(&cursor)?),
+            None => None,
+        };
+        let limit = limit.clamp(1, self.limits.max_history);
+        // fetch one extra row to find out whether there is an older page
+        let mut messages = match conversation {
+            Conversation::Direct { uid: peer } => self.dao.get_direct_messages(uid, peer, before, limit + 1).await?,
+            Conversation::Channel { cid } => {
+                if !self.dao.exists_member(uid, cid).await? {
+                    return Err(Error::Forbidden("you are not a member of this channel".into()));
+                }
+                self.dao.get_channel_messages(cid, before, limit + 1).await?
+            }
+        };
+        let cursor = if messages.len() as i64 > limit {
+            messages.truncate(limit as usize);
+            messages.last().map(|m| encode_cursor(m.id))
+        } else {
+            None
+        };
+        messages.reverse();
+        Ok(Output::History(History { conversation, messages, cursor }))
+    }
+
+    fn reply(&self, ctx: &mut WebsocketContext<Self>, id: String, handler: impl Future<Output = Result<Output, Error>> + 'static) {
+        let addr = ctx.address();
+        ctx.spawn(
+            async move {
+                let output = OutputMessage::from(handler.await).in_reply_to(id);
+                addr.do_send(output);
+            }
+            .into_actor(self),
+        );
+    }
+
+    fn push(&self, uid: i32, output: OutputMessage) {
+        self.presence.send(uid, output);
+    }
+
+    async fn broadcast_presence(self, uid: i32, online: bool) {
+        if let Err(e) = self.dao.update_last_seen(uid).await {
+            log::error!("failed to update last seen of user {}: {}", uid, e);
+        }
+        match self.dao.get_friends(uid).await {
+            Err(e) => log::error!("failed to get friends of user {}: {}", uid, e),
+            Ok(friends) => {
+                let last_seen = Utc::now();
+                for friend in friends {
+                    let peer = if friend.user_a == uid { friend.user_b } else { friend.user_a };
+                    self.push(peer, OutputMessage::new(Output::PresenceChanged { uid, online, last_seen }));
                 }
-                return LoginResponse {
-                    phone,
-                    token: "".into(),
-                    err: "invalid phone or password".into(),
-                };
             }
         }
     }
 
-    async fn handle_find_user(self, phone: String) -> OutputMessage {
-        match self.dao.get_account(phone).await {
-            Err(e) => {
-                return OutputMessage {
-                    output: Output::Notify {
-                        level: NotifyLevel::Error,
-                        content: e.to_string(),
-                    },
+    async fn participants(&self, uid: i32, conversation: Conversation) -> Result<Vec<i32>, Error> {
+        match conversation {
+            Conversation::Direct { uid: peer } => {
+                if !self.dao.exists_friend(uid, peer).await? {
+                    return Err(Error::Forbidden("recipient is not your friend".into()));
                 }
+                Ok(vec![peer])
             }
-            Ok(acct) => match acct {
-                None => {
-                    return OutputMessage {
-                        output: Output::FindUserResponse { user: None },
-                    }
+            Conversation::Channel { cid } => {
+                if !self.dao.exists_member(uid, cid).await? {
+                    return Err(Error::Forbidden("you are not a member of this channel".into()));
                 }
-                Some(a) => match self.dao.get_user_by_account_id(a.id).await {
-                    Err(e) => {
-                        return OutputMessage {
-                            output: Output::Notify {
-                                level: NotifyLevel::Error,
-                                content: e.to_string(),
-                            },
-                        }
-                    }
-                    Ok(u) => {
-                        return OutputMessage {
-                            output: Output::FindUserResponse { user: u },
-                        }
-                    }
-                },
+                Ok(self.dao.get_members(cid).await?.into_iter().map(|m| m.user).filter(|&u| u != uid).collect())
+            }
+        }
+    }
+
+    async fn handle_mark_read(self, uid: i32, conversation: Conversation, seq: i32) -> Result<Output, Error> {
+        let participants = self.participants(uid, conversation).await?;
+        if seq > self.dao.get_last_seq(uid, conversation).await?.unwrap_or_default() {
+            return Err(Error::Validation("seq is beyond the last message of the conversation".into()));
+        }
+        self.dao.update_read_mark(uid, conversation, seq).await?;
+        let receipt = conversation.for_peers_of(uid);
+        for participant in participants {
+            self.push(participant, OutputMessage::new(Output::ReadReceipt { conversation: receipt, uid, seq }));
+        }
+        self.presence.send_except(uid, &self.sid, OutputMessage::new(Output::ReadReceipt { conversation, uid, seq }));
+        Ok(Output::MarkReadResponse { conversation, seq })
+    }
+
+    async fn handle_list_conversations(self, uid: i32) -> Result<Output, Error> {
+        let conversations = self.dao.get_conversations(uid).await?;
+        Ok(Output::Conversations { conversations })
+    }
+
+    async fn handle_typing(self, uid: i32, conversation: Conversation) -> Result<Output, Error> {
+        for participant in self.participants(uid, conversation).await? {
+            self.push(
+                participant,
+                OutputMessage::new(Output::Typing {
+                    conversation: conversation.for_peers_of(uid),
+                    uid,
+                    expires_in: TYPING_EXPIRY.as_millis() as u64,
+                }),
+            );
+        }
+        Ok(Output::TypingResponse { conversation })
+    }
+
+    async fn handle_logout(self, token: Option<String>, refresh_token: Option<String>) -> Result<Output, Error> {
+        if let Some(refresh_token) = refresh_token {
+            self.dao.take_refresh_token(self.author.hash_refresh_token(&refresh_token)).await?;
+        }
+        if let Some(token) = token {
+            self.author.revoke(token)?;
+        }
+        Ok(Output::LogoutResponse)
+    }
+
+    // the generation is kept in the database so that the tokens stay revoked across a restart
+    async fn revoke_all(&self, uid: i32) -> Result<(), Error> {
+        let generation = self.dao.increase_token_generation(uid).await?;
+        self.author.revoke_all(uid, generation);
+        Ok(())
+    }
+
+    async fn handle_logout_all(self, uid: i32) -> Result<Output, Error> {
+        self.dao.delete_refresh_tokens(uid).await?;
+        self.revoke_all(uid).await?;
+        Ok(Output::LogoutAllResponse)
+    }
+
+    // registers this session as one of the user's, which makes it reachable for pushes
+    fn bind(&mut self, uid: i32, ctx: &mut WebsocketContext<Self>) {
+        let session = Session {
+            id: self.sid.clone(),
+            device: self.device.clone(),
+            connected_at: Utc::now(),
+        };
+        self.presence.send_except(uid, &self.sid, OutputMessage::new(Output::SessionStarted { session: session.clone() }));
+        if self.presence.connect(uid, session, ctx.address()) {
+            actix::spawn(self.clone().broadcast_presence(uid, true));
+        }
+        let addr = ctx.address();
+        let handler = self.clone().handle_deliver_pending(uid);
+        ctx.spawn(
+            async move {
+                for output in handler.await {
+                    addr.do_send(output);
+                }
+            }
+            .into_actor(self),
+        );
+    }
+
+    // a message without token runs as the user this session is bound to, the bound token is still
+    // checked so that an expired or revoked one stops working until the client refreshes it
+    fn authenticate(&mut self, ctx: &mut WebsocketContext<Self>, token: Option<String>) -> Result<i32, Error> {
+        let token = token.or_else(|| self.token.clone()).ok_or(Error::Auth("unauthenticated".into()))?;
+        let uid = self.author.verify(token.clone())?;
+        // presence and sessions are kept for the bound user, commands must not run as anyone else
+        if self.uid.is_some_and(|bound| bound != uid) {
+            return Err(Error::Auth("token belongs to another user".into()));
+        }
+        self.token = Some(token);
+        // the first token on a session that had none at the handshake binds it, as a login does
+        if self.uid.is_none() {
+            self.uid = Some(uid);
+            self.bind(uid, ctx);
+        }
+        Ok(uid)
+    }
+
+    async fn request_code(&self, phone: String) -> Result<(), Error> {
+        if self.dao.get_account(phone.clone()).await?.is_none() {
+            return Err(Error::NotFound("phone not registered".into()));
+        }
+        if let Some(sent) = self.dao.get_verification_code(phone.clone()).await? {
+            let wait = sent.sent_at + chrono::Duration::seconds(self.limits.code_resend_interval_secs) - Utc::now();
+            if wait > chrono::Duration::zero() {
+                return Err(Error::TooManyRequests(wait.num_seconds() as u64 + 1));
+            }
+        }
+        let code = gen_code();
+        self.dao
+            .upsert_verification_code(VerificationCodeInsert {
+                phone: phone.clone(),
+                code: code.clone(),
+                expires_at: Utc::now() + chrono::Duration::seconds(self.limits.code_ttl_secs),
+            })
+            .await?;
+        self.sms
+            .send(phone, format!("your verification code is {}, valid for {} minutes", code, self.limits.code_ttl_secs / 60))
+            .await
+    }
+
+    async fn handle_request_code(self, phone: String) -> Result<Output, Error> {
+        self.request_code(phone.clone()).await?;
+        Ok(Output::RequestCodeResponse(RequestCodeResponse { phone }))
+    }
+
+    // every check counts as an attempt, the code is consumed once it matches
+    async fn check_code(&self, phone: String, code: String) -> Result<(), Error> {
+        let sent = self.dao.increase_code_attempts(phone).await?.ok_or(Error::Auth("invalid code".into()))?;
+        if sent.expires_at <= Utc::now() {
+            return Err(Error::Auth("code expired".into()));
+        }
+        if sent.attempts > self.limits.max_code_attempts {
+            return Err(Error::Auth("too many attempts, request a new code".into()));
+        }
+        if sent.code != code {
+            return Err(Error::Auth("invalid code".into()));
+        }
+        self.dao.delete_verification_code(sent.id).await?;
+        Ok(())
+    }
+
+    async fn login_with_code(&self, phone: String, code: String) -> Result<(String, String), Error> {
+        self.check_code(phone.clone(), code).await?;
+        let account = self.dao.get_account(phone).await?.ok_or(Error::NotFound("account not exists".into()))?;
+        let user = self.dao.get_user_by_account_id(account.id).await?.ok_or(Error::NotFound("user not exists".into()))?;
+        self.issue_tokens(user.id).await
+    }
+
+    async fn handle_login_with_code(self, phone: String, code: String) -> Result<LoginResponse, Error> {
+        let res = self.throttled(&phone, self.login_with_code(phone.clone(), code)).await;
+        login_response(phone, res)
+    }
+
+    // every password gets a fresh salt, and whatever was issued under the old password stops working
+    async fn set_password(&self, account: i32, uid: i32, password: String) -> Result<(), Error> {
+        let salt = gen_salt();
+        let hashed = self.hash_password(password, salt.clone()).await?;
+        self.dao.update_password(account, hashed, salt).await?;
+        self.dao.delete_refresh_tokens(uid).await?;
+        self.revoke_all(uid).await?;
+        Ok(())
+    }
+
+    async fn handle_change_password(self, uid: i32, old: String, new: String) -> Result<Output, Error> {
+        if new.is_empty() {
+            return Err(Error::Validation("password is required".into()));
+        }
+        let user = self.dao.get_user(uid).await?.ok_or(Error::NotFound("user not exists".into()))?;
+        let account = self.dao.get_account_by_id(user.account).await?.ok_or(Error::NotFound("account not exists".into()))?;
+        if !self.verify_password(old, account.salt, account.password).await? {
+            return Err(Error::Auth("invalid password".into()));
+        }
+        self.set_password(account.id, uid, new).await?;
+        let (token, refresh_token) = self.issue_tokens(uid).await?;
+        Ok(Output::ChangePasswordResponse { token, refresh_token })
+    }
+
+    async fn reset_password(&self, phone: String, code: String, password: String) -> Result<i32, Error> {
+        if password.is_empty() {
+            return Err(Error::Validation("password is required".into()));
+        }
+        self.check_code(phone.clone(), code).await?;
+        let account = self.dao.get_account(phone).await?.ok_or(Error::NotFound("account not exists".into()))?;
+        let user = self.dao.get_user_by_account_id(account.id).await?.ok_or(Error::NotFound("user not exists".into()))?;
+        self.set_password(account.id, user.id, password).await?;
+        Ok(user.id)
+    }
+
+    async fn handle_reset_password(self, phone: String, code: String, password: String) -> Result<Output, Error> {
+        let uid = self.throttled(&phone, self.reset_password(phone.clone(), code, password)).await?;
+        // the session asking for the reset has to get the response, its token is revoked all the same
+        self.presence.terminate_except(uid, &self.sid);
+        Ok(Output::ResetPasswordResponse(ResetPasswordResponse { phone }))
+    }
+
+    fn write(&self, ctx: &mut WebsocketContext<Self>, message: OutputMessage) {
+        let envelope = OutputEnvelope { v: self.version, message };
+        match self.codec {
+            Codec::Json => ctx.text(serde_json::to_string(&envelope).unwrap()),
+            Codec::Msgpack => ctx.binary(rmp_serde::to_vec_named(&envelope).unwrap()),
+        }
+    }
+
+    // text frames are always JSON and binary frames MessagePack, whatever the session writes
+    fn handle_input(&mut self, ctx: &mut WebsocketContext<Self>, input: Result<InputMessage, (Error, Option<FrameId>)>) {
+        match input {
+            Err((e, frame)) => match frame.and_then(|frame| frame.id) {
+                Some(id) => self.write(ctx, OutputMessage::error(e).in_reply_to(id)),
+                None => self.write(ctx, OutputMessage::error(e)),
+            },
+            Ok(msg) if !PROTOCOL_VERSIONS.contains(&msg.v) => {
+                let e = Error::Validation(format!("unsupported protocol version {}", msg.v));
+                self.write(ctx, OutputMessage::error(e).in_reply_to(msg.id))
+            }
+            Ok(msg) if msg.input.is_public() => self.handle_public(ctx, msg.id, msg.input),
+            Ok(msg) => match self.authenticate(ctx, msg.token) {
+                Ok(uid) => ctx.address().do_send(Command {
+                    id: msg.id,
+                    from: uid,
+                    input: msg.input,
+                }),
+                Err(e) => self.write(ctx, OutputMessage::error(e).in_reply_to(msg.id)),
             },
         }
     }
+
+    // the inputs that work without a token, most of them are how a client gets one
+    fn handle_public(&mut self, ctx: &mut WebsocketContext<Self>, id: String, input: Input) {
+        match input {
+            Input::Hello { versions, codecs } => {
+                let output = match versions.into_iter().filter(|v| PROTOCOL_VERSIONS.contains(v)).max() {
+                    // the reply is already written with the new codec
+                    Some(version) => {
+                        self.version = version;
+                        self.codec = codecs.unwrap_or_default().iter().find_map(|name| Codec::from_name(name)).unwrap_or_default();
+                        OutputMessage::new(Output::Hello { version, codec: self.codec })
+                    }
+                    None => OutputMessage::error(Error::Validation(format!("no supported protocol version, the server speaks {:?}", PROTOCOL_VERSIONS))),
+                };
+                self.write(ctx, output.in_reply_to(id));
+            }
+            Input::Register(msg) => {
+                self.device = msg.device.unwrap_or_default();
+                let handler = self.clone().handle_register(msg.phone, msg.password, msg.name);
+                self.reply_login(ctx, id, handler);
+            }
+            Input::Login(msg) => {
+                self.device = msg.device.unwrap_or_default();
+                let handler = self.clone().handle_login(msg.phone, msg.password);
+                self.reply_login(ctx, id, handler);
+            }
+            Input::LoginWithCode(msg) => {
+                self.device = msg.device.unwrap_or_default();
+                let handler = self.clone().handle_login_with_code(msg.phone, msg.code);
+                self.reply_login(ctx, id, handler);
+            }
+            Input::RefreshToken(msg) => {
+                let handler = self.clone().handle_refresh(msg.refresh_token);
+                self.reply_login(ctx, id, handler);
+            }
+            Input::RequestCode(msg) => {
+                let handler = self.clone().handle_request_code(msg.phone);
+                self.reply(ctx, id, handler);
+            }
+            Input::ResetPassword(msg) => {
+                let handler = self.clone().handle_reset_password(msg.phone, msg.code, msg.password);
+                self.reply(ctx, id, handler);
+            }
+            _ => {}
+        }
+    }
+
+    // a successful login binds the session to the user before the client sees the response
+    fn reply_login(&self, ctx: &mut WebsocketContext<Self>, id: String, handler: impl Future<Output = Result<LoginResponse, Error>> + 'static) {
+        ctx.spawn(handler.into_actor(self).map(move |res, act, ctx| {
+            let res = match res {
+                Err(e) => return act.write(ctx, OutputMessage::error(e).in_reply_to(id)),
+                Ok(res) => res,
+            };
+            if let Ok(uid) = act.author.verify(res.token.clone()) {
+                act.token = Some(res.token.clone());
+                if let Some(old) = act.uid.replace(uid) {
+                    if old != uid && act.presence.disconnect(old, &act.sid) {
+                        actix::spawn(act.clone().broadcast_presence(old, false));
+                    }
+                }
+                act.bind(uid, ctx);
+            }
+            act.write(ctx, OutputMessage::new(Output::LoginResponse(res)).in_reply_to(id));
+        }));
+    }
 }
 
-impl<A, D> Actor for WS<A, D>
+impl<A, D, S> Actor for WS<A, D, S>
 where
     A: Author + Clone + Unpin + 'static,
     D: Dao + Clone + Unpin + 'static,
+    S: SmsSender + Clone + Unpin + 'static,
 {
     type Context = WebsocketContext<Self>;
+
+    // the uid is already known when the token came with the handshake
+    fn started(&mut self, ctx: &mut Self::Context) {
+        if let Some(uid) = self.uid {
+            self.bind(uid, ctx);
+        }
+    }
+
+    fn stopped(&mut self, _: &mut Self::Context) {
+        if let Some(uid) = self.uid {
+            if self.presence.disconnect(uid, &self.sid) {
+                actix::spawn(self.clone().broadcast_presence(uid, false));
+            }
+        }
+    }
 }
 
-impl<A, D> StreamHandler<Result<Message, ProtocolError>> for WS<A, D>
+impl<A, D, S> StreamHandler<Result<Message, ProtocolError>> for WS<A, D, S>
 where
     A: Author + Clone + Unpin + 'static,
     D: Dao + Clone + Unpin + 'static,
+    S: SmsSender + Clone + Unpin + 'static,
 {
     fn handle(&mut self, item: Result<Message, ProtocolError>, ctx: &mut Self::Context) {
-        let item = item.unwrap();
+        let item = match item {
+            Ok(item) => item,
+            Err(e) => {
+                log::warn!("websocket protocol error in session {}: {}", self.sid, e);
+                return ctx.stop();
+            }
+        };
         match item {
             Message::Text(s) => {
-                if let Ok(login) = serde_json::from_str::<Login>(&s) {
-                    ctx.address().do_send(login);
-                    return;
-                }
-                match serde_json::from_str::<InputMessage>(&s) {
-                    Ok(msg) => match self.author.verify(msg.token) {
-                        Ok(uid) => ctx.address().do_send(Command { from: uid, input: msg.input }),
-                        Err(e) => ctx.text(
-                            serde_json::to_string(&OutputMessage {
-                                output: Output::Notify {
-                                    level: NotifyLevel::Error,
-                                    content: e.to_string(),
-                                },
-                            })
-                            .unwrap(),
-                        ),
-                    },
-                    Err(e) => ctx.text(
-                        serde_json::to_string(&OutputMessage {
-                            output: Output::Notify {
-                                level: NotifyLevel::Error,
-                                content: e.to_string(),
-                            },
-                        })
-                        .unwrap(),
-                    ),
-                }
-                let msg: InputMessage = serde_json::from_str(&s).unwrap();
-                match msg.input {
-                    _ => {}
-                }
+                let input = serde_json::from_str::<InputMessage>(&s).map_err(|e| (Error::Validation(e.to_string()), serde_json::from_str::<FrameId>(&s).ok()));
+                self.handle_input(ctx, input);
+            }
+            Message::Binary(b) => {
+                let input = rmp_serde::from_slice::<InputMessage>(&b).map_err(|e| (Error::Validation(e.to_string()), rmp_serde::from_slice::<FrameId>(&b).ok()));
+                self.handle_input(ctx, input);
             }
             Message::Ping(m) => ctx.pong(&m),
-            Message::Close(_) => {
-                self.users.write().unwrap().remove(&self.name);
+            Message::Close(reason) => {
+                ctx.close(reason);
+                ctx.stop();
             }
             _ => {}
         }
     }
 }
 
-impl<A, D> Handler<Command> for WS<A, D>
+impl<A, D, S> Handler<Command> for WS<A, D, S>
 where
     A: Author + Clone + Unpin + 'static,
     D: Dao + Clone + Unpin + 'static,
+    S: SmsSender + Clone + Unpin + 'static,
 {
     type Result = ();
     fn handle(&mut self, msg: Command, ctx: &mut Self::Context) -> Self::Result {
-        match msg.input {
+        let Command { id, from, input } = msg;
+        match input {
             Input::FindUser { phone } => {
-                let addr = ctx.address();
                 let handler = self.clone().handle_find_user(phone);
+                self.reply(ctx, id, handler);
+            }
+            Input::AddFriend { phone } => {
+                let handler = self.clone().handle_add_friend(from, phone);
+                self.reply(ctx, id, handler);
+            }
+            Input::FindChannel { q } => {
+                let handler = self.clone().handle_find_channel(q);
+                self.reply(ctx, id, handler);
+            }
+            Input::FriendApplications => {
+                let handler = self.clone().handle_friend_applications(from);
+                self.reply(ctx, id, handler);
+            }
+            Input::ApproveFriend { uid } => {
+                let handler = self.clone().handle_settle_friend(from, uid, true);
+                self.reply(ctx, id, handler);
+            }
+            Input::RejectFriend { uid } => {
+                let handler = self.clone().handle_settle_friend(from, uid, false);
+                self.reply(ctx, id, handler);
+            }
+            Input::JoinChannel { cid } => {
+                let handler = self.clone().handle_join_channel(from, cid);
+                self.reply(ctx, id, handler);
+            }
+            Input::JoinApplications => {
+                let handler = self.clone().handle_join_applications(from);
+                self.reply(ctx, id, handler);
+            }
+            Input::ApproveJoin { cid, uid } => {
+                let handler = self.clone().handle_settle_join(from, cid, uid, true);
+                self.reply(ctx, id, handler);
+            }
+            Input::RejectJoin { cid, uid } => {
+                let handler = self.clone().handle_settle_join(from, cid, uid, false);
+                self.reply(ctx, id, handler);
+            }
+            Input::CreateChannel { name, description } => {
+                let handler = self.clone().handle_create_channel(from, name, description);
+                self.reply(ctx, id, handler);
+            }
+            Input::UpdateChannel { cid, name, description } => {
+                let handler = self.clone().handle_update_channel(from, cid, name, description);
+                self.reply(ctx, id, handler);
+            }
+            Input::DeleteChannel { cid } => {
+                let handler = self.clone().handle_delete_channel(from, cid);
+                self.reply(ctx, id, handler);
+            }
+            Input::PromoteMember { cid, uid, role } => {
+                let handler = self.clone().handle_change_role(from, cid, uid, role, true);
+                self.reply(ctx, id, handler);
+            }
+            Input::DemoteMember { cid, uid, role } => {
+                let handler = self.clone().handle_change_role(from, cid, uid, role, false);
+                self.reply(ctx, id, handler);
+            }
+            Input::TransferOwnership { cid, uid } => {
+                let handler = self.clone().handle_transfer_ownership(from, cid, uid);
+                self.reply(ctx, id, handler);
+            }
+            Input::SendDirectMessage { to, content } => {
+                let handler = self.clone().handle_send_direct_message(from, id.clone(), to, content);
+                self.reply(ctx, id, handler);
+            }
+            Input::SendChannelMessage { cid, content } => {
+                let handler = self.clone().handle_send_channel_message(from, id.clone(), cid, content);
+                self.reply(ctx, id, handler);
+            }
+            Input::AckMessage { mid } => {
+                let handler = self.clone().handle_ack_message(from, mid);
+                self.reply(ctx, id, handler);
+            }
+            Input::FetchHistory { conversation, before, limit } => {
+                let handler = self.clone().handle_fetch_history(from, conversation, before, limit);
+                self.reply(ctx, id, handler);
+            }
+            Input::MarkRead { conversation, seq } => {
+                let handler = self.clone().handle_mark_read(from, conversation, seq);
+                self.reply(ctx, id, handler);
+            }
+            Input::ListConversations => {
+                let handler = self.clone().handle_list_conversations(from);
+                self.reply(ctx, id, handler);
+            }
+            Input::ListSessions => {
+                let output = Output::Sessions {
+                    current: self.sid.clone(),
+                    sessions: self.presence.sessions(from),
+                };
+                self.reply(ctx, id, ready(Ok(output)));
+            }
+            Input::TerminateSession { sid } => {
+                let output = match self.presence.session(from, &sid) {
+                    None => Err(Error::NotFound("session not exists".into())),
+                    Some(addr) => {
+                        addr.do_send(Terminate);
+                        Ok(Output::TerminateSessionResponse { sid })
+                    }
+                };
+                self.reply(ctx, id, ready(output));
+            }
+            Input::Typing { conversation } => {
+                // within the throttle the peers have been told already
+                if self.typing.get(&conversation).is_some_and(|t| t.elapsed() < TYPING_THROTTLE) {
+                    return self.reply(ctx, id, ready(Ok(Output::TypingResponse { conversation })));
+                }
+                let handler = self.clone().handle_typing(from, conversation);
+                ctx.spawn(handler.into_actor(self).map(move |res, act, ctx| {
+                    // only a relayed indicator starts the throttle, a refused one is checked again next time
+                    if res.is_ok() {
+                        act.typing.insert(conversation, Instant::now());
+                        ctx.run_later(TYPING_THROTTLE, move |act, _| {
+                            if act.typing.get(&conversation).is_some_and(|t| t.elapsed() >= TYPING_THROTTLE) {
+                                act.typing.remove(&conversation);
+                            }
+                        });
+                    }
+                    act.write(ctx, OutputMessage::from(res).in_reply_to(id));
+                }));
+            }
+            Input::Logout { refresh_token } => {
+                let addr = ctx.address();
+                let handler = self.clone().handle_logout(self.token.clone(), refresh_token);
                 ctx.spawn(
                     async move {
-                        let output = handler.await;
-                        addr.do_send(output);
+                        let res = handler.await;
+                        let logged_out = res.is_ok();
+                        addr.do_send(OutputMessage::from(res).in_reply_to(id));
+                        if logged_out {
+                            addr.do_send(Terminate);
+                        }
                     }
                     .into_actor(self),
                 );
             }
+            Input::LogoutAll => {
+                let addr = ctx.address();
+                let presence = self.presence.clone();
+                let handler = self.clone().handle_logout_all(from);
+                ctx.spawn(
+                    async move {
+                        let res = handler.await;
+                        let logged_out = res.is_ok();
+                        addr.do_send(OutputMessage::from(res).in_reply_to(id));
+                        // every session of the user is closed, this one included, right after the reply
+                        if logged_out {
+                            presence.terminate(from);
+                        }
+                    }
+                    .into_actor(self),
+                );
+            }
+            Input::ChangePassword { old, new } => {
+                let handler = self.clone().handle_change_password(from, old, new);
+                ctx.spawn(handler.into_actor(self).map(move |res, act, ctx| {
+                    // the other sessions are logged out, this one carries on with the new token
+                    if let Ok(Output::ChangePasswordResponse { token, .. }) = &res {
+                        act.token = Some(token.clone());
+                        act.presence.terminate_except(from, &act.sid);
+                    }
+                    act.write(ctx, OutputMessage::from(res).in_reply_to(id));
+                }));
+            }
             _ => {}
         }
     }
 }
 
-impl<A, D> Handler<OutputMessage> for WS<A, D>
+impl<A, D, S> Handler<OutputMessage> for WS<A, D, S>
 where
     A: Author + Clone + Unpin + 'static,
     D: Dao + Clone + Unpin + 'static,
+    S: SmsSender + Clone + Unpin + 'static,
 {
     type Result = ();
-    fn handle(&mut self, msg: OutputMessage, ctx: &mut Self::Context) -> Self::Result {}
+    fn handle(&mut self, msg: OutputMessage, ctx: &mut Self::Context) -> Self::Result {
+        self.write(ctx, msg)
+    }
 }
 
-impl<A, D> Handler<Login> for WS<A, D>
+impl<A, D, S> Handler<Terminate> for WS<A, D, S>
 where
     A: Author + Clone + Unpin + 'static,
     D: Dao + Clone + Unpin + 'static,
+    S: SmsSender + Clone + Unpin + 'static,
 {
     type Result = ();
-    fn handle(&mut self, msg: Login, ctx: &mut Self::Context) -> Self::Result {
-        let addr = ctx.address();
-        let h = self.clone().handle_login(msg.phone, msg.password);
-        ctx.spawn(
-            Box::pin(async move {
-                let msg = h.await;
-                addr.do_send(msg);
-            })
-            .into_actor(&self.clone()),
+    fn handle(&mut self, _: Terminate, ctx: &mut Self::Context) -> Self::Result {
+        self.write(
+            ctx,
+            OutputMessage::new(Output::Notify {
+                level: NotifyLevel::Warning,
+                content: "session terminated".into(),
+            }),
         );
+        ctx.close(Some(CloseCode::Normal.into()));
+        ctx.stop();
     }
 }
 
-impl<A, D> Handler<LoginResponse> for WS<A, D>
-where
-    A: Author + Clone + Unpin + 'static,
-    D: Dao + Clone + Unpin + 'static,
-{
-    type Result = ();
-    fn handle(&mut self, msg: LoginResponse, ctx: &mut Self::Context) -> Self::Result {
-        if msg.token != "" {
-            let mut users = self.users.write().unwrap();
-            if let Some(addr) = users.get_mut(&msg.phone).unwrap() {
-                addr.do_send(RepeatLoginWarning);
-            }
-            users.insert(msg.phone.clone(), Some(ctx.address()));
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cursor_round_trips() {
+        for id in [0, 1, 255, i32::MAX] {
+            assert_eq!(decode_cursor(&encode_cursor(id)).unwrap(), id);
         }
-        ctx.text(serde_json::to_string(&msg).unwrap())
+        assert_eq!(encode_cursor(255), "000000ff");
     }
-}
 
-impl<A, D> Handler<RepeatLoginWarning> for WS<A, D>
-where
-    A: Author + Clone + Unpin + 'static,
-    D: Dao + Clone + Unpin + 'static,
-{
-    type Result = ();
-    fn handle(&mut self, _: RepeatLoginWarning, ctx: &mut Self::Context) -> Self::Result {
-        ctx.text(
-            serde_json::to_string(&OutputMessage {
-                output: Output::Notify {
-                    level: NotifyLevel::Warning,
-                    content: "repeat login".into(),
-                },
-            })
-            .unwrap(),
-        )
+    #[test]
+    fn malformed_cursor_is_rejected() {
+        assert!(decode_cursor("zz").is_err());
+        assert!(decode_cursor("").is_err());
     }
 }